@@ -0,0 +1,14 @@
+use aproxiflock::config::build_config;
+use aproxiflock::headless::run_comparison;
+
+fn main() {
+    let config = build_config().unwrap_or_else(|err| {
+        println!("Failure building configuration:");
+        err.exit()
+    });
+
+    run_comparison(config).unwrap_or_else(|err| {
+        println!("Failure running neighbour comparison");
+        err.exit()
+    });
+}
@@ -0,0 +1,14 @@
+use aproxiflock::config::build_config;
+use aproxiflock::headless::run_headless;
+
+fn main() {
+    let config = build_config().unwrap_or_else(|err| {
+        println!("Failure building configuration:");
+        err.exit()
+    });
+
+    run_headless(config).unwrap_or_else(|err| {
+        println!("Failure running headless simulation");
+        err.exit()
+    });
+}
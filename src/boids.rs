@@ -1,43 +1,119 @@
 use std::error::{self, Error};
-use std::ffi::{CStr, CString};
+use std::ffi::CString;
+use std::fs::File;
 use std::num::NonZeroU32;
 use std::{fmt, process};
 
 use gl;
 
 use winit::dpi::LogicalSize;
+use winit::error::OsError;
 use winit::event::VirtualKeyCode;
 use winit::event_loop::EventLoop;
+use winit::platform::run_return::EventLoopExtRunReturn;
 use winit::window::{Fullscreen, Window, WindowBuilder};
 
 use raw_window_handle::{HasRawDisplayHandle, HasRawWindowHandle};
 
-use glutin::config::ConfigTemplateBuilder;
-use glutin::context::{ContextApi, ContextAttributesBuilder, GlProfile, Version};
+use glutin::config::{Config, ConfigTemplateBuilder};
+use glutin::context::{
+    ContextApi, ContextAttributesBuilder, GlProfile, NotCurrentContext, Version,
+};
 use glutin::display::{Display, DisplayApiPreference};
 use glutin::prelude::*;
-use glutin::surface::{
-    Surface, SurfaceAttributes, SurfaceAttributesBuilder, SwapInterval, WindowSurface,
-};
+use glutin::surface::{SurfaceAttributesBuilder, SwapInterval, WindowSurface};
 
+use crate::config::{self, ConfigSource};
 use crate::event::{BoidControlEvent, EventFilter};
 use crate::fps::{FpsCache, FpsCounter};
-use crate::glx; //TODO: Rename this module
+use crate::glx::{self, ShaderError}; //TODO: Rename this module
+use crate::headless::HeadlessConfig;
+use crate::kernel::NeighbourKernel;
+use crate::obstacle::Obstacle;
+use crate::predator::PredatorConfig;
+use crate::record::{Recorder, RecordingError, Replay};
 use crate::render::{Renderer, RendererConfig};
-use crate::system::{FlockingConfig, FlockingSystem};
+use crate::species::{Interactions, SpeciesConfig};
+use crate::system::{
+    Boundary, FlockingConfig, FlockingSystem, ForcePartition, NeighbourSearch, ResizeMode, SortMode,
+};
+use crate::timestep::FixedTimestep;
+use crate::tune::{self, Tuner};
+use crate::watch::FileWatcher;
 
 const TITLE: &str = "rusty-boids";
 const CACHE_FPS_MS: u64 = 500;
+const CHECK_CONFIG_MS: u64 = 500;
+pub(crate) const STEPS_PER_SECOND: u32 = 60;
+const PREDATOR_SIZE_SCALE: f32 = 4.;
+
+// Contexts to ask for, in order, until the driver gives us one the shaders can run on
+const CONTEXT_REQUESTS: [(Option<Version>, GlProfile); 4] = [
+    (Some(Version::new(3, 3)), GlProfile::Core),
+    (None, GlProfile::Core),
+    (Some(Version::new(3, 3)), GlProfile::Compatibility),
+    (None, GlProfile::Compatibility),
+];
 
 #[derive(Debug)]
 pub enum SimulatorError {
-    Window(String),
+    UnsupportedGlApi(GlApi),
+    Display(glutin::error::Error),
+    Config(glutin::error::Error),
+    NoSuitableConfig,
+    Context(glutin::error::Error),
+    Window(OsError),
+    Surface(glutin::error::Error),
+    GlLoading(&'static str),
+    Shader(ShaderError),
+    Recording(RecordingError),
 }
 
 impl fmt::Display for SimulatorError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            SimulatorError::Window(ref err) => write!(f, "Window error, {}", err),
+            SimulatorError::UnsupportedGlApi(api) => {
+                write!(
+                    f,
+                    "The {:?} OpenGL API is not available on this platform",
+                    api
+                )
+            }
+            SimulatorError::Display(ref err) => write!(
+                f,
+                "Could not connect to a display with OpenGL support, {}",
+                err
+            ),
+            SimulatorError::Config(ref err) => {
+                write!(f, "Could not list the display's OpenGL configs, {}", err)
+            }
+            SimulatorError::NoSuitableConfig => write!(
+                f,
+                "No OpenGL config on this display has an alpha channel without multisampling"
+            ),
+            SimulatorError::Context(ref err) => {
+                let tried: Vec<_> = CONTEXT_REQUESTS
+                    .iter()
+                    .map(|&(version, profile)| describe_context(version, profile))
+                    .collect();
+                write!(
+                    f,
+                    "Could not set up an OpenGL context, tried {}, {}",
+                    tried.join(", "),
+                    err
+                )
+            }
+            SimulatorError::Window(ref err) => write!(f, "Could not open a window, {}", err),
+            SimulatorError::Surface(ref err) => {
+                write!(f, "Could not draw to the window surface, {}", err)
+            }
+            SimulatorError::GlLoading(name) => write!(
+                f,
+                "The OpenGL driver does not provide {}, which drawing the flock needs",
+                name
+            ),
+            SimulatorError::Shader(ref err) => write!(f, "Could not build shaders, {}", err),
+            SimulatorError::Recording(ref err) => write!(f, "Recording error, {}", err),
         }
     }
 }
@@ -45,7 +121,16 @@ impl fmt::Display for SimulatorError {
 impl error::Error for SimulatorError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match *self {
-            SimulatorError::Window(..) => None,
+            SimulatorError::Display(ref err)
+            | SimulatorError::Config(ref err)
+            | SimulatorError::Context(ref err)
+            | SimulatorError::Surface(ref err) => Some(err),
+            SimulatorError::Window(ref err) => Some(err),
+            SimulatorError::Shader(ref err) => Some(err),
+            SimulatorError::Recording(ref err) => Some(err),
+            SimulatorError::UnsupportedGlApi(..)
+            | SimulatorError::NoSuitableConfig
+            | SimulatorError::GlLoading(..) => None,
         }
     }
 }
@@ -60,6 +145,8 @@ impl SimulatorError {
 pub struct SimulationConfig {
     pub boid_count: u32,
     pub window_size: WindowSize,
+    pub resize: ResizeMode,
+    pub gl_api: GlApi,
     pub debug: bool,
     pub max_speed: f32,
     pub max_force: f32,
@@ -70,7 +157,39 @@ pub struct SimulationConfig {
     pub sep_radius: f32,
     pub ali_radius: f32,
     pub coh_radius: f32,
+    /// When empty the whole flock is a single species, using the parameters above.
+    pub species: Vec<SpeciesConfig>,
+    pub interactions: Interactions,
+    pub avoid_weight: f32,
+    pub avoid_radius: f32,
+    pub obstacles: Vec<Obstacle>,
+    pub boundary: Boundary,
+    pub boundary_margin: f32,
+    pub neighbours: NeighbourSearch,
+    pub sort: SortMode,
+    pub sort_passes: usize,
+    pub sort_target: f32,
+    pub kernel: NeighbourKernel,
+    pub kernel_samples: usize,
+    pub fov: f32,
+    pub force_update_fraction: f32,
+    pub force_partition: ForcePartition,
+    pub predators: PredatorConfig,
     pub boid_size: f32,
+    pub threads: usize,
+    pub seed: Option<u64>,
+    pub headless: HeadlessConfig,
+    pub snapshot_path: String,
+    /// File parameters tuned with the keyboard are written to.
+    pub tuned_path: String,
+    /// Pick up changes to the config file while running.
+    pub reload: bool,
+    pub record_path: Option<String>,
+    pub replay_path: Option<String>,
+    /// The preset underneath the rest of the config, if any.
+    pub preset: Option<String>,
+    /// How to build the config again, when it came from a file or the command line.
+    pub source: Option<ConfigSource>,
 }
 
 impl Default for SimulationConfig {
@@ -78,38 +197,102 @@ impl Default for SimulationConfig {
         SimulationConfig {
             boid_count: 1000,
             window_size: WindowSize::Dimensions((800, 800)),
+            resize: ResizeMode::Extend,
+            gl_api: GlApi::Auto,
             debug: false,
-            max_speed: 2.5,
-            max_force: 0.4,
-            mouse_weight: 600.,
+            max_speed: 150.,
+            max_force: 1440.,
+            mouse_weight: 2_160_000.,
             sep_radius: 6.,
             ali_radius: 11.5,
             coh_radius: 11.5,
             sep_weight: 1.5,
             ali_weight: 1.0,
             coh_weight: 1.0,
+            species: Vec::new(),
+            interactions: Interactions::default(),
+            avoid_weight: 3.0,
+            avoid_radius: 20.,
+            obstacles: Vec::new(),
+            boundary: Boundary::Wrap,
+            boundary_margin: 50.,
+            neighbours: NeighbourSearch::Grid,
+            sort: SortMode::Full,
+            sort_passes: 3,
+            sort_target: 0.9,
+            kernel: NeighbourKernel::Forward,
+            kernel_samples: 10,
+            fov: 360.,
+            force_update_fraction: 1.,
+            force_partition: ForcePartition::Rows,
+            predators: PredatorConfig::default(),
             boid_size: 3.0,
+            threads: 0,
+            seed: None,
+            headless: HeadlessConfig::default(),
+            snapshot_path: String::from("flock.snapshot"),
+            tuned_path: String::from("tuned.toml"),
+            reload: true,
+            record_path: None,
+            replay_path: None,
+            preset: None,
+            source: None,
+        }
+    }
+}
+
+impl SimulationConfig {
+    /// The species making up the flock, falling back to one built from the flocking parameters.
+    pub fn species(&self) -> Vec<SpeciesConfig> {
+        if !self.species.is_empty() {
+            return self.species.clone();
         }
+        vec![SpeciesConfig {
+            name: String::from("boid"),
+            share: 1.,
+            max_speed: self.max_speed,
+            max_force: self.max_force,
+            sep_weight: self.sep_weight,
+            ali_weight: self.ali_weight,
+            coh_weight: self.coh_weight,
+            sep_radius: self.sep_radius,
+            ali_radius: self.ali_radius,
+            coh_radius: self.coh_radius,
+            colour: None,
+        }]
     }
 }
 
-fn build_flocking_config(
+pub(crate) fn build_flocking_config(
     sim_config: &SimulationConfig,
-    window_size: &WindowSizeInfo,
+    width: f32,
+    height: f32,
 ) -> FlockingConfig {
     FlockingConfig {
         boid_count: sim_config.boid_count,
-        width: window_size.width,
-        height: window_size.height,
-        max_speed: sim_config.max_speed,
-        max_force: sim_config.max_force,
+        width,
+        height,
+        species: sim_config.species(),
+        interactions: sim_config.interactions.clone(),
         mouse_weight: sim_config.mouse_weight,
-        sep_weight: sim_config.sep_weight,
-        ali_weight: sim_config.ali_weight,
-        coh_weight: sim_config.coh_weight,
-        sep_radius: sim_config.sep_radius,
-        ali_radius: sim_config.ali_radius,
-        coh_radius: sim_config.coh_radius,
+        avoid_weight: sim_config.avoid_weight,
+        avoid_radius: sim_config.avoid_radius,
+        obstacles: sim_config.obstacles.clone(),
+        boundary: sim_config.boundary,
+        boundary_margin: sim_config.boundary_margin,
+        resize: sim_config.resize,
+        neighbours: sim_config.neighbours,
+        sort: sim_config.sort,
+        sort_passes: sim_config.sort_passes,
+        sort_target: sim_config.sort_target,
+        kernel: sim_config.kernel,
+        kernel_samples: sim_config.kernel_samples,
+        fov: sim_config.fov,
+        force_update_fraction: sim_config.force_update_fraction,
+        force_partition: sim_config.force_partition,
+        predators: sim_config.predators.clone(),
+        threads: sim_config.threads,
+        seed: sim_config.seed,
     }
 }
 
@@ -117,11 +300,16 @@ fn build_render_config(
     sim_config: &SimulationConfig,
     window_size: &WindowSizeInfo,
 ) -> RendererConfig {
+    let species = sim_config.species();
     RendererConfig {
         width: window_size.width,
         height: window_size.height,
         boid_size: sim_config.boid_size * (window_size.hidpi_factor as f32),
-        max_speed: sim_config.max_speed,
+        predator_size: sim_config.boid_size
+            * PREDATOR_SIZE_SCALE
+            * (window_size.hidpi_factor as f32),
+        max_speed: species.iter().map(|s| s.max_speed).fold(0., f32::max),
+        species_colours: species.iter().map(|s| s.colour).collect(),
     }
 }
 
@@ -130,13 +318,61 @@ pub enum WindowSize {
     Dimensions((u32, u32)),
 }
 
-pub fn run_simulation(sim_config: SimulationConfig) -> Result<(), SimulatorError> {
+/// The platform API used to get hold of an OpenGL context.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GlApi {
+    /// The usual choice for the platform, falling back to the alternative where there is one.
+    Auto,
+    Egl,
+    Glx,
+    Wgl,
+    Cgl,
+}
+
+#[cfg(target_os = "macos")]
+fn display_api_preference(api: GlApi) -> Result<DisplayApiPreference, SimulatorError> {
+    match api {
+        GlApi::Auto | GlApi::Cgl => Ok(DisplayApiPreference::Cgl),
+        api => Err(SimulatorError::UnsupportedGlApi(api)),
+    }
+}
+
+// Without a window to hand, WGL goes without extensions and may only manage a compatibility context
+#[cfg(windows)]
+fn display_api_preference(api: GlApi) -> Result<DisplayApiPreference, SimulatorError> {
+    match api {
+        GlApi::Auto => Ok(DisplayApiPreference::WglThenEgl(None)),
+        GlApi::Wgl => Ok(DisplayApiPreference::Wgl(None)),
+        GlApi::Egl => Ok(DisplayApiPreference::Egl),
+        api => Err(SimulatorError::UnsupportedGlApi(api)),
+    }
+}
+
+// GLX shares Xlib's error handler with winit, so it has to hook into winit's
+#[cfg(all(unix, not(target_os = "macos")))]
+fn display_api_preference(api: GlApi) -> Result<DisplayApiPreference, SimulatorError> {
+    use winit::platform::unix::register_xlib_error_hook;
+    match api {
+        GlApi::Auto => Ok(DisplayApiPreference::EglThenGlx(Box::new(
+            register_xlib_error_hook,
+        ))),
+        GlApi::Egl => Ok(DisplayApiPreference::Egl),
+        GlApi::Glx => Ok(DisplayApiPreference::Glx(Box::new(
+            register_xlib_error_hook,
+        ))),
+        api => Err(SimulatorError::UnsupportedGlApi(api)),
+    }
+}
+
+pub fn run_simulation(mut sim_config: SimulationConfig) -> Result<(), SimulatorError> {
     let mut event_loop = EventLoop::new();
 
     let raw_display = event_loop.raw_display_handle();
 
-    //TODO: Handle error
-    let display = unsafe { Display::new(raw_display, DisplayApiPreference::Cgl).unwrap() };
+    let preference = display_api_preference(sim_config.gl_api)?;
+    let display =
+        unsafe { Display::new(raw_display, preference) }.map_err(SimulatorError::Display)?;
     println!("Running on: {}", display.version_string());
 
     let template = ConfigTemplateBuilder::new()
@@ -145,31 +381,21 @@ pub fn run_simulation(sim_config: SimulationConfig) -> Result<(), SimulatorError
         .with_multisampling(0)
         .build();
 
-    // TODO: Handle error
     let config = unsafe { display.find_configs(template) }
-        .unwrap()
+        .map_err(SimulatorError::Config)?
         .next()
-        .unwrap();
+        .ok_or(SimulatorError::NoSuitableConfig)?;
 
     println!("Picked a config with {} samples", config.num_samples());
     println!("config {:?}", config);
 
-    let context_attributes = ContextAttributesBuilder::new()
-        .with_context_api(ContextApi::OpenGl(Some(Version::new(3, 3))))
-        .with_profile(GlProfile::Core)
-        .build(None);
-
-    // TODO: Handle error
     // TODO: Can we do this later?
-    let non_current_gl_context = unsafe {
-        display
-            .create_context(&config, &context_attributes)
-            .unwrap()
-    };
+    let non_current_gl_context = create_context(&display, &config)?;
 
     let window_builder = WindowBuilder::new()
         .with_title(TITLE)
         .with_transparent(false);
+    let window_builder = with_config_visual(window_builder, &config);
 
     let window_builder = match sim_config.window_size {
         WindowSize::Fullscreen => {
@@ -178,77 +404,228 @@ pub fn run_simulation(sim_config: SimulationConfig) -> Result<(), SimulatorError
         WindowSize::Dimensions((w, h)) => window_builder.with_inner_size(LogicalSize::new(w, h)),
     };
 
-    // TODO: Handle error
-    let window = window_builder.build(&event_loop).unwrap();
+    let window = window_builder
+        .build(&event_loop)
+        .map_err(SimulatorError::Window)?;
 
     let window_size = get_window_size_info(&window)?;
 
     //TODO: Use above window_size instead
     let (width, height): (u32, u32) = window.inner_size().into();
     let raw_window_handle = window.raw_window_handle();
-    let attributes = SurfaceAttributesBuilder::<WindowSurface>::new().build(
-        raw_window_handle,
-        NonZeroU32::new(width).unwrap(),
-        NonZeroU32::new(height).unwrap(),
-    );
+    let (width, height) = surface_size(width, height).ok_or_else(|| {
+        let reason = "the window opened with no area to draw in";
+        SimulatorError::Surface(glutin::error::ErrorKind::NotSupported(reason).into())
+    })?;
+    let attributes =
+        SurfaceAttributesBuilder::<WindowSurface>::new().build(raw_window_handle, width, height);
 
-    // TODO: Handle error
-    let surface = unsafe { display.create_window_surface(&config, &attributes).unwrap() };
+    let surface = unsafe { display.create_window_surface(&config, &attributes) }
+        .map_err(SimulatorError::Surface)?;
 
-    // TODO: Handle error
-    let gl_context = non_current_gl_context.make_current(&surface).unwrap();
+    let gl_context = non_current_gl_context
+        .make_current(&surface)
+        .map_err(SimulatorError::Context)?;
 
-    //TODO: Handle error
     surface
         .set_swap_interval(&gl_context, SwapInterval::Wait(NonZeroU32::new(1).unwrap()))
-        .unwrap();
+        .map_err(SimulatorError::Surface)?;
 
     gl::load_with(|symbol| {
         let symbol = CString::new(symbol).unwrap();
         display.get_proc_address(symbol.as_c_str()) as *const _
     });
+    check_gl_loaded()?;
 
     if sim_config.debug {
         print_debug_info();
     }
 
-    let flock_conf = build_flocking_config(&sim_config, &window_size);
+    let flock_conf = build_flocking_config(&sim_config, window_size.width, window_size.height);
     let render_conf = build_render_config(&sim_config, &window_size);
     let mut simulation = FlockingSystem::new(flock_conf);
+    println!("Flock seed: {}", simulation.seed());
     simulation.randomise();
-    let renderer = Renderer::new(render_conf);
-    renderer.init_pipeline();
+    let mut replay = match sim_config.replay_path {
+        Some(ref path) => Some(Replay::open(path).map_err(SimulatorError::Recording)?),
+        None => None,
+    };
+    let mut recorder = match sim_config.record_path {
+        Some(ref path) => {
+            Some(Recorder::create(path, &simulation).map_err(SimulatorError::Recording)?)
+        }
+        None => None,
+    };
+    let mut renderer = Renderer::new(render_conf).map_err(SimulatorError::Shader)?;
+    renderer.init_pipeline().map_err(SimulatorError::Shader)?;
     let mut fps_counter = FpsCounter::new();
     let mut fps_cacher = FpsCache::new(CACHE_FPS_MS);
-    let mut running = true;
+    let mut config_watcher = match sim_config.source {
+        Some(ref source) if sim_config.reload => source
+            .config_path()
+            .map(|path| FileWatcher::new(path, CHECK_CONFIG_MS)),
+        _ => None,
+    };
+    let mut timestep = FixedTimestep::new(STEPS_PER_SECOND);
     let mut paused = false;
-    let event_filter = EventFilter::new(window_size.hidpi_factor);
-    event_loop.run(move |event, event_loop_window_target, control_flow| {
+    let mut tuner = Tuner::new();
+    let mut readout = String::new();
+    let mut result = Ok(());
+    let event_filter = EventFilter::new();
+    event_loop.run_return(|event, _, control_flow| {
         control_flow.set_wait();
+        let steps = timestep.tick();
         if !paused {
-            simulation.update();
+            for _ in 0..steps {
+                if let Some(ref mut r) = replay {
+                    if let Err(err) = r.apply_due(&mut simulation, apply_event) {
+                        println!("Abandoning replay: {}", err);
+                        replay = None;
+                    }
+                }
+                simulation.update(timestep.step_secs());
+            }
+        }
+        if replay.as_ref().is_some_and(Replay::finished) {
+            println!("Replay finished at step {}", simulation.steps());
+            replay = None;
         }
 
-        //TODO: Hook into close requested
-
-        match event_filter.process(event) {
-            //TODO: Wire up exit
-            //Some(BoidControlEvent::Stop) => running = false,
-            Some(BoidControlEvent::Pause) => paused = !paused,
-            Some(event) => handle_event(&mut simulation, event),
-            _ => (),
+        let reconfigured = match event_filter.process(event) {
+            Some(BoidControlEvent::Stop) => {
+                control_flow.set_exit();
+                return;
+            }
+            Some(BoidControlEvent::Pause) => {
+                paused = !paused;
+                false
+            }
+            Some(BoidControlEvent::Resize(w, h)) => match surface_size(w, h) {
+                // Minimised windows have no size, the world stays as it was until restored
+                None => false,
+                Some((width, height)) => {
+                    surface.resize(&gl_context, width, height);
+                    renderer.resize_viewport(w, h);
+                    // A replay brings its own resizes, to keep the world as it was recorded
+                    if replay.is_none() {
+                        let event = BoidControlEvent::Resize(w, h);
+                        handle_event(
+                            &mut simulation,
+                            &mut sim_config,
+                            &mut recorder,
+                            &mut tuner,
+                            event,
+                        )
+                    } else {
+                        false
+                    }
+                }
+            },
+            // Whilst replaying the recorded events are in control
+            Some(_) if replay.is_some() => false,
+            Some(event) => handle_event(
+                &mut simulation,
+                &mut sim_config,
+                &mut recorder,
+                &mut tuner,
+                event,
+            ),
+            None => false,
+        };
+        // Config changes whilst replaying would only be undone by the next recorded restore
+        let reloaded = replay.is_none()
+            && config_watcher.as_mut().is_some_and(FileWatcher::poll)
+            && reload_config(&mut simulation, &mut sim_config, &mut recorder);
+        if reconfigured || reloaded {
+            let render_conf = build_render_config(&sim_config, &window_size);
+            renderer.reconfigure(render_conf);
         }
-        renderer.render(&simulation.boids());
-        //TODO: Deal with errors
+        renderer.render(&simulation);
         window.request_redraw();
-        surface.swap_buffers(&gl_context).unwrap();
+        if let Err(err) = surface.swap_buffers(&gl_context) {
+            result = Err(SimulatorError::Surface(err));
+            control_flow.set_exit();
+            return;
+        }
         fps_counter.tick();
+        let new_readout = tuner.readout(&sim_config);
+        if new_readout != readout {
+            readout = new_readout;
+            window.set_title(&window_title(fps_counter.average_fps(), &readout));
+        }
         fps_cacher.poll(&fps_counter, |new_fps| {
-            let title = format!("{} - {:02} fps", TITLE, new_fps);
-            window.set_title(&title);
+            window.set_title(&window_title(new_fps, &readout));
         });
     });
-    Ok(())
+
+    // Leave the display as it was found, and make sure the whole run made it to disk
+    window.set_fullscreen(None);
+    if let Some(recorder) = recorder {
+        recorder.finish().map_err(SimulatorError::Recording)?;
+    }
+    result
+}
+
+fn create_context(display: &Display, config: &Config) -> Result<NotCurrentContext, SimulatorError> {
+    let mut last_err = None;
+    for &(version, profile) in CONTEXT_REQUESTS.iter() {
+        let context_attributes = ContextAttributesBuilder::new()
+            .with_context_api(ContextApi::OpenGl(version))
+            .with_profile(profile)
+            .build(None);
+        match unsafe { display.create_context(config, &context_attributes) } {
+            Ok(context) => {
+                println!("Created an {} context", describe_context(version, profile));
+                return Ok(context);
+            }
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(SimulatorError::Context(
+        last_err.expect("at least one context is requested"),
+    ))
+}
+
+fn describe_context(version: Option<Version>, profile: GlProfile) -> String {
+    let version = version.map_or(String::from("latest"), |v| {
+        format!("{}.{}", v.major, v.minor)
+    });
+    format!("OpenGL {} {:?}", version, profile)
+}
+
+// X11 windows have to be created with the visual of the config they will be drawn with
+#[cfg(all(unix, not(target_os = "macos")))]
+fn with_config_visual(window_builder: WindowBuilder, config: &Config) -> WindowBuilder {
+    use glutin::platform::x11::X11GlConfigExt;
+    use winit::platform::unix::WindowBuilderExtUnix;
+    match config.x11_visual() {
+        Some(visual) => window_builder.with_x11_visual(visual.into_raw()),
+        None => window_builder,
+    }
+}
+
+#[cfg(not(all(unix, not(target_os = "macos"))))]
+fn with_config_visual(window_builder: WindowBuilder, _config: &Config) -> WindowBuilder {
+    window_builder
+}
+
+// Loading never fails outright, missing functions only show up when they are called
+fn check_gl_loaded() -> Result<(), SimulatorError> {
+    let required = [
+        ("glGetString", gl::GetString::is_loaded()),
+        ("glCreateShader", gl::CreateShader::is_loaded()),
+        ("glGenVertexArrays", gl::GenVertexArrays::is_loaded()),
+        ("glBufferData", gl::BufferData::is_loaded()),
+        (
+            "glVertexAttribIPointer",
+            gl::VertexAttribIPointer::is_loaded(),
+        ),
+        ("glDrawArrays", gl::DrawArrays::is_loaded()),
+    ];
+    match required.iter().find(|(_, loaded)| !loaded) {
+        Some(&(name, _)) => Err(SimulatorError::GlLoading(name)),
+        None => Ok(()),
+    }
 }
 
 struct WindowSizeInfo {
@@ -257,6 +634,11 @@ struct WindowSizeInfo {
     hidpi_factor: f64,
 }
 
+// A surface can not be made or resized to nothing
+fn surface_size(width: u32, height: u32) -> Option<(NonZeroU32, NonZeroU32)> {
+    Some((NonZeroU32::new(width)?, NonZeroU32::new(height)?))
+}
+
 fn get_window_size_info(window: &Window) -> Result<WindowSizeInfo, SimulatorError> {
     let hidpi_factor = window.scale_factor();
     let physical_size = window.inner_size();
@@ -268,7 +650,174 @@ fn get_window_size_info(window: &Window) -> Result<WindowSizeInfo, SimulatorErro
     })
 }
 
-fn handle_event(simulation: &mut FlockingSystem, event: BoidControlEvent) {
+// Returns whether the config was replaced, so that whatever was built from it needs updating
+fn handle_event(
+    simulation: &mut FlockingSystem,
+    sim_config: &mut SimulationConfig,
+    recorder: &mut Option<Recorder>,
+    tuner: &mut Tuner,
+    event: BoidControlEvent,
+) -> bool {
+    let mut reconfigured = false;
+    let recorded = match event {
+        BoidControlEvent::Key(VirtualKeyCode::S) => {
+            save_snapshot(simulation, &sim_config.snapshot_path);
+            Ok(())
+        }
+        BoidControlEvent::Key(VirtualKeyCode::L) => {
+            let step = simulation.steps();
+            match (
+                load_snapshot(simulation, &sim_config.snapshot_path),
+                recorder.as_mut(),
+            ) {
+                (true, Some(r)) => r.record_restore(step, simulation),
+                _ => Ok(()),
+            }
+        }
+        BoidControlEvent::Key(VirtualKeyCode::Tab) => {
+            tuner.next_param();
+            Ok(())
+        }
+        BoidControlEvent::Key(VirtualKeyCode::Right) => {
+            tuner.next_species(sim_config);
+            Ok(())
+        }
+        BoidControlEvent::Key(VirtualKeyCode::Left) => {
+            tuner.prev_species(sim_config);
+            Ok(())
+        }
+        BoidControlEvent::Key(key @ (VirtualKeyCode::Up | VirtualKeyCode::Down)) => {
+            let presses = if key == VirtualKeyCode::Up { 1. } else { -1. };
+            tuner.nudge(sim_config, simulation, presses);
+            reconfigured = true;
+            match recorder.as_mut() {
+                Some(r) => r.record_restore(simulation.steps(), simulation),
+                None => Ok(()),
+            }
+        }
+        BoidControlEvent::Key(VirtualKeyCode::P) => {
+            match tune::save_tuned(sim_config, &sim_config.tuned_path) {
+                Ok(()) => println!("Saved tuned parameters to {}", sim_config.tuned_path),
+                Err(err) => println!("Failed to save tuned parameters: {}", err),
+            }
+            Ok(())
+        }
+        BoidControlEvent::Key(key) if preset_key(key).is_some() => {
+            let preset = preset_key(key).unwrap();
+            reconfigured = switch_preset(simulation, sim_config, preset);
+            match (reconfigured, recorder.as_mut()) {
+                (true, Some(r)) => r.record_restore(simulation.steps(), simulation),
+                _ => Ok(()),
+            }
+        }
+        event => {
+            let recorded = match recorder {
+                Some(r) => r.record_event(simulation.steps(), &event),
+                None => Ok(()),
+            };
+            apply_event(simulation, event);
+            recorded
+        }
+    };
+    if let Err(err) = recorded {
+        println!("Stopped recording: {}", err);
+        *recorder = None;
+    }
+    reconfigured
+}
+
+// Returns whether the config was replaced, like handle_event
+fn reload_config(
+    simulation: &mut FlockingSystem,
+    sim_config: &mut SimulationConfig,
+    recorder: &mut Option<Recorder>,
+) -> bool {
+    let source = match sim_config.source {
+        Some(ref source) => source,
+        None => return false,
+    };
+    // A preset picked while running, and values tuned since, are kept over the file
+    let new_config = match source.build() {
+        Ok(new_config) => new_config,
+        Err(err) => {
+            println!("Keeping the previous config, could not reload it: {}", err);
+            return false;
+        }
+    };
+    if new_config.boid_count != sim_config.boid_count {
+        println!("Changes to boid_count take effect on restart");
+    }
+    reconfigure(simulation, sim_config, new_config);
+    println!("Reloaded config");
+    if let Some(r) = recorder.as_mut() {
+        if let Err(err) = r.record_restore(simulation.steps(), simulation) {
+            println!("Stopped recording: {}", err);
+            *recorder = None;
+        }
+    }
+    true
+}
+
+// Only the flocking parameters are taken on, the boids and the world stay as they are
+fn reconfigure(
+    simulation: &mut FlockingSystem,
+    sim_config: &mut SimulationConfig,
+    new_config: SimulationConfig,
+) {
+    let (width, height) = (simulation.width(), simulation.height());
+    simulation.reconfigure(build_flocking_config(&new_config, width, height));
+    *sim_config = new_config;
+}
+
+fn window_title(fps: u32, readout: &str) -> String {
+    format!("{} - {:02} fps - {}", TITLE, fps, readout)
+}
+
+// The number keys pick the bundled presets in order, 0 goes back to no preset at all
+fn preset_key(key: VirtualKeyCode) -> Option<Option<&'static str>> {
+    let index = match key {
+        VirtualKeyCode::Key0 => return Some(None),
+        VirtualKeyCode::Key1 => 0,
+        VirtualKeyCode::Key2 => 1,
+        VirtualKeyCode::Key3 => 2,
+        VirtualKeyCode::Key4 => 3,
+        VirtualKeyCode::Key5 => 4,
+        VirtualKeyCode::Key6 => 5,
+        VirtualKeyCode::Key7 => 6,
+        VirtualKeyCode::Key8 => 7,
+        VirtualKeyCode::Key9 => 8,
+        _ => return None,
+    };
+    config::PRESETS.get(index).map(|&(name, _)| Some(name))
+}
+
+fn switch_preset(
+    simulation: &mut FlockingSystem,
+    sim_config: &mut SimulationConfig,
+    preset: Option<&str>,
+) -> bool {
+    let source = match sim_config.source {
+        Some(ref source) => source,
+        None => {
+            println!("Can not switch preset, the config can not be built again");
+            return false;
+        }
+    };
+    match source.build_with_preset(preset) {
+        Ok(new_config) => {
+            reconfigure(simulation, sim_config, new_config);
+            println!("Switched to preset: {}", preset.unwrap_or("none"));
+            true
+        }
+        Err(err) => {
+            println!("Could not switch preset: {}", err);
+            false
+        }
+    }
+}
+
+/// Apply the events that change the simulation, these are what get recorded and replayed.
+pub(crate) fn apply_event(simulation: &mut FlockingSystem, event: BoidControlEvent) {
     match event {
         BoidControlEvent::MouseMove(x, y) => simulation.set_mouse(x, y),
         BoidControlEvent::MousePress => simulation.enable_mouse_attraction(),
@@ -276,10 +825,37 @@ fn handle_event(simulation: &mut FlockingSystem, event: BoidControlEvent) {
         BoidControlEvent::Key(VirtualKeyCode::R) => simulation.randomise(),
         BoidControlEvent::Key(VirtualKeyCode::F) => simulation.zeroise(),
         BoidControlEvent::Key(VirtualKeyCode::C) => simulation.centralise(),
+        BoidControlEvent::Resize(w, h) => simulation.resize(w as f32, h as f32),
         _ => (),
     }
 }
 
+fn save_snapshot(simulation: &FlockingSystem, path: &str) {
+    let result = File::create(path)
+        .map_err(|err| err.into())
+        .and_then(|file| simulation.save_snapshot(file));
+    match result {
+        Ok(()) => println!("Saved snapshot to {}", path),
+        Err(err) => println!("Failed to save snapshot: {}", err),
+    }
+}
+
+fn load_snapshot(simulation: &mut FlockingSystem, path: &str) -> bool {
+    let result = File::open(path)
+        .map_err(|err| err.into())
+        .and_then(|file| simulation.load_snapshot(file));
+    match result {
+        Ok(()) => {
+            println!("Loaded snapshot from {}", path);
+            true
+        }
+        Err(err) => {
+            println!("Failed to load snapshot: {}", err);
+            false
+        }
+    }
+}
+
 fn print_debug_info() {
     println!("Vendor: {}", glx::get_gl_str(gl::VENDOR));
     println!("Renderer: {}", glx::get_gl_str(gl::RENDERER));
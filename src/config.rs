@@ -1,16 +1,125 @@
-use std::{env, fmt, fs::File, io, io::prelude::*, process};
+use std::{env, fmt, fs::File, io, io::prelude::*, process, str::FromStr};
 
-use crate::boids::{SimulationConfig, WindowSize};
+use serde::de::DeserializeOwned;
+
+use crate::boids::{GlApi, SimulationConfig, WindowSize};
+use crate::kernel::NeighbourKernel;
+use crate::obstacle::Obstacle;
+use crate::predator::ChaseStrategy;
+use crate::species::SpeciesConfig;
+use crate::system::{Boundary, ForcePartition, NeighbourSearch, ResizeMode, SortMode};
+use crate::tune::TunedValues;
+use crate::validate::{self, InvalidField};
 
 use toml;
 
+/// The flock presets bundled with the program, each a partial config.
+pub const PRESETS: &[(&str, &str)] = &[
+    ("murmuration", include_str!("../presets/murmuration.toml")),
+    ("fish_school", include_str!("../presets/fish_school.toml")),
+    ("swarm", include_str!("../presets/swarm.toml")),
+    ("lazy_drift", include_str!("../presets/lazy_drift.toml")),
+];
+
+/// Build the config from the file given on the command line, overridden by any flags given.
 pub fn build_config() -> Result<SimulationConfig, ConfigError> {
-    let mut builder = ConfigBuilder::new();
-    let args = env::args();
-    let config_path = parse_args(args);
-    //TODO: Is this now overcomplicated
-    builder.apply(UserSimulationConfig::from_toml_file(&config_path)?);
-    Ok(builder.build())
+    let mut args = env::args();
+    let exec = args.next().unwrap_or_else(|| String::from("boids"));
+    let (config_path, overrides) = match parse_args(args)? {
+        Args::Run {
+            config_path,
+            overrides,
+        } => (config_path, overrides),
+        Args::Help => {
+            print_usage(&exec);
+            process::exit(0);
+        }
+        Args::Version => {
+            println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+            process::exit(0);
+        }
+    };
+
+    let source = ConfigSource {
+        config_path,
+        overrides: *overrides,
+        ..ConfigSource::default()
+    };
+    source.build()
+}
+
+/// Where a config came from, so that it can be built again while the simulation runs.
+#[derive(Clone, Default)]
+pub struct ConfigSource {
+    config_path: Option<String>,
+    overrides: UserSimulationConfig,
+    // Picked while running, over the one from the file or command line. Can be no preset at all.
+    picked_preset: Option<Option<String>>,
+    tuned: TunedValues,
+}
+
+impl ConfigSource {
+    /// Build the config again, reading the config file as it is now. A preset picked while
+    /// running and values tuned since are kept.
+    pub fn build(&self) -> Result<SimulationConfig, ConfigError> {
+        let file = self.read_file()?;
+        let preset = match self.picked_preset {
+            Some(ref preset) => preset.clone(),
+            None => self
+                .overrides
+                .preset
+                .clone()
+                .or_else(|| file.preset.clone()),
+        };
+        self.build_from(file, preset)
+    }
+
+    /// Build the config again, with a different preset underneath it, or none at all. The preset
+    /// is taken as it is, values tuned so far are let go.
+    pub fn build_with_preset(&self, preset: Option<&str>) -> Result<SimulationConfig, ConfigError> {
+        let source = ConfigSource {
+            picked_preset: Some(preset.map(String::from)),
+            tuned: TunedValues::default(),
+            ..self.clone()
+        };
+        source.build()
+    }
+
+    /// Values tuned while running, laid over the rest of the config.
+    pub(crate) fn tuned_mut(&mut self) -> &mut TunedValues {
+        &mut self.tuned
+    }
+
+    /// The config file, if there is one.
+    pub fn config_path(&self) -> Option<&str> {
+        self.config_path.as_deref()
+    }
+
+    fn read_file(&self) -> Result<UserSimulationConfig, ConfigError> {
+        match self.config_path {
+            Some(ref path) => UserSimulationConfig::from_toml_file(path),
+            None => Ok(UserSimulationConfig::default()),
+        }
+    }
+
+    // Presets go under the file, which goes under the command line, then tuned values over it all
+    fn build_from(
+        &self,
+        file: UserSimulationConfig,
+        preset: Option<String>,
+    ) -> Result<SimulationConfig, ConfigError> {
+        let mut builder = ConfigBuilder::new();
+        if let Some(ref name) = preset {
+            builder.apply(UserSimulationConfig::from_preset(name)?);
+        }
+        builder.apply(file);
+        builder.apply(self.overrides.clone());
+        let mut config = builder.build()?;
+        self.tuned.apply(&mut config);
+        config.preset = preset;
+        config.source = Some(self.clone());
+        Ok(config)
+    }
 }
 
 fn merge<T>(existing: &mut T, candidate: Option<T>) {
@@ -32,27 +141,242 @@ fn window_size(window_conf: Option<UserWindowConfig>) -> Option<WindowSize> {
     }
 }
 
-fn parse_args(args: impl IntoIterator<Item = String>) -> String {
+fn obstacle(uc_obstacle: UserObstacle) -> Obstacle {
+    match uc_obstacle {
+        UserObstacle::Circle { centre, radius } => Obstacle::Circle { centre, radius },
+        UserObstacle::Rect { min, max } => Obstacle::Rect { min, max },
+        UserObstacle::Wall { start, end } => Obstacle::Wall { start, end },
+    }
+}
+
+// Species inherit whatever they leave out from the flocking parameters
+fn species(base: &SpeciesConfig, index: usize, uc_species: UserSpeciesConfig) -> SpeciesConfig {
+    let mut s = base.clone();
+    s.name = format!("species-{}", index + 1);
+    merge(&mut s.name, uc_species.name);
+    merge(&mut s.share, uc_species.share);
+    merge(&mut s.max_speed, uc_species.max_speed);
+    merge(&mut s.max_force, uc_species.max_force);
+    merge(&mut s.sep_weight, uc_species.sep_weight);
+    merge(&mut s.ali_weight, uc_species.ali_weight);
+    merge(&mut s.coh_weight, uc_species.coh_weight);
+    merge(&mut s.sep_radius, uc_species.sep_radius);
+    merge(&mut s.ali_radius, uc_species.ali_radius);
+    merge(&mut s.coh_radius, uc_species.coh_radius);
+    merge(&mut s.colour, uc_species.colour.map(Some));
+    s
+}
+
+enum Args {
+    Run {
+        config_path: Option<String>,
+        overrides: Box<UserSimulationConfig>,
+    },
+    Help,
+    Version,
+}
+
+// A flag on the command line, and how it sets its part of the config. Switches have no value.
+struct Flag {
+    long: &'static str,
+    short: Option<char>,
+    value: Option<&'static str>,
+    help: &'static str,
+    set: fn(&mut UserSimulationConfig, &str) -> Result<(), String>,
+}
+
+fn window(c: &mut UserSimulationConfig) -> &mut UserWindowConfig {
+    c.window.get_or_insert_with(Default::default)
+}
+
+fn flocking(c: &mut UserSimulationConfig) -> &mut UserFlockingConfig {
+    c.flocking.get_or_insert_with(Default::default)
+}
+
+fn predators(c: &mut UserSimulationConfig) -> &mut UserPredatorConfig {
+    c.predators.get_or_insert_with(Default::default)
+}
+
+fn recording(c: &mut UserSimulationConfig) -> &mut UserRecordingConfig {
+    c.recording.get_or_insert_with(Default::default)
+}
+
+fn headless(c: &mut UserSimulationConfig) -> &mut UserHeadlessConfig {
+    c.headless.get_or_insert_with(Default::default)
+}
+
+fn number<T: FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("'{}' is not a valid number", value))
+}
+
+// Named options are spelt the same as in the config file
+fn named<T: DeserializeOwned>(value: &str) -> Result<T, String> {
+    toml::Value::String(value.to_owned())
+        .try_into()
+        .map_err(|_| format!("'{}' is not a valid choice", value))
+}
+
+fn dimensions(value: &str) -> Result<(u32, u32), String> {
+    match value.split_once('x') {
+        Some((w, h)) => Ok((number(w)?, number(h)?)),
+        None => Err(format!("'{}' is not of the form WIDTHxHEIGHT", value)),
+    }
+}
+
+const CONFIG_FLAG: &str = "config";
+const HELP_FLAG: &str = "help";
+const VERSION_FLAG: &str = "version";
+
+#[rustfmt::skip]
+const FLAGS: &[Flag] = &[
+    Flag { long: CONFIG_FLAG, short: Some('c'), value: Some("FILE"), help: "Read config from a toml file", set: |_, _| Ok(()) },
+    Flag { long: HELP_FLAG, short: Some('h'), value: None, help: "Print this help", set: |_, _| Ok(()) },
+    Flag { long: VERSION_FLAG, short: Some('V'), value: None, help: "Print the version", set: |_, _| Ok(()) },
+    Flag { long: "preset", short: Some('p'), value: Some("NAME"), help: "Start from a built in preset", set: |c, v| { c.preset = Some(v.to_owned()); Ok(()) } },
+    Flag { long: "boid-count", short: Some('n'), value: Some("N"), help: "Desired number of boids", set: |c, v| { c.boid_count = Some(number(v)?); Ok(()) } },
+    Flag { long: "boid-size", short: None, value: Some("SIZE"), help: "Size each boid is rendered", set: |c, v| { c.boid_size = Some(number(v)?); Ok(()) } },
+    Flag { long: "debug", short: None, value: None, help: "Print debug information", set: |c, _| { c.debug = Some(true); Ok(()) } },
+    Flag { long: "threads", short: None, value: Some("N"), help: "Threads to use, 0 for one per core", set: |c, v| { c.threads = Some(number(v)?); Ok(()) } },
+    Flag { long: "snapshot", short: None, value: Some("FILE"), help: "File snapshots are saved to and loaded from", set: |c, v| { c.snapshot = Some(v.to_owned()); Ok(()) } },
+    Flag { long: "tuned", short: None, value: Some("FILE"), help: "File tuned parameters are written to", set: |c, v| { c.tuned = Some(v.to_owned()); Ok(()) } },
+    Flag { long: "no-reload", short: None, value: None, help: "Ignore changes to the config file while running", set: |c, _| { c.reload = Some(false); Ok(()) } },
+    Flag { long: "size", short: None, value: Some("WxH"), help: "Size of the window", set: |c, v| { window(c).size = Some(dimensions(v)?); Ok(()) } },
+    Flag { long: "fullscreen", short: Some('f'), value: None, help: "Run fullscreen", set: |c, _| { window(c).fullscreen = Some(true); Ok(()) } },
+    Flag { long: "resize", short: None, value: Some("MODE"), help: "On resize, \"extend\" or \"stretch\" the world", set: |c, v| { window(c).resize = Some(named(v)?); Ok(()) } },
+    Flag { long: "gl-api", short: None, value: Some("API"), help: "\"auto\", \"egl\", \"glx\", \"wgl\" or \"cgl\"", set: |c, v| { window(c).gl_api = Some(named(v)?); Ok(()) } },
+    Flag { long: "max-speed", short: None, value: Some("SPEED"), help: "Maximum speed of a boid", set: |c, v| { flocking(c).max_speed = Some(number(v)?); Ok(()) } },
+    Flag { long: "max-force", short: None, value: Some("FORCE"), help: "Maximum force on a boid", set: |c, v| { flocking(c).max_force = Some(number(v)?); Ok(()) } },
+    Flag { long: "mouse-weight", short: None, value: Some("WEIGHT"), help: "Weight of mouse interactions", set: |c, v| { flocking(c).mouse_weight = Some(number(v)?); Ok(()) } },
+    Flag { long: "sep-weight", short: None, value: Some("WEIGHT"), help: "Weight of separation", set: |c, v| { flocking(c).sep_weight = Some(number(v)?); Ok(()) } },
+    Flag { long: "ali-weight", short: None, value: Some("WEIGHT"), help: "Weight of alignment", set: |c, v| { flocking(c).ali_weight = Some(number(v)?); Ok(()) } },
+    Flag { long: "coh-weight", short: None, value: Some("WEIGHT"), help: "Weight of cohesion", set: |c, v| { flocking(c).coh_weight = Some(number(v)?); Ok(()) } },
+    Flag { long: "sep-radius", short: None, value: Some("DIST"), help: "Distance neighbours are avoided within", set: |c, v| { flocking(c).sep_radius = Some(number(v)?); Ok(()) } },
+    Flag { long: "ali-radius", short: None, value: Some("DIST"), help: "Distance neighbours are aligned with within", set: |c, v| { flocking(c).ali_radius = Some(number(v)?); Ok(()) } },
+    Flag { long: "coh-radius", short: None, value: Some("DIST"), help: "Distance neighbours are steered towards within", set: |c, v| { flocking(c).coh_radius = Some(number(v)?); Ok(()) } },
+    Flag { long: "avoid-weight", short: None, value: Some("WEIGHT"), help: "Weight of obstacle avoidance", set: |c, v| { flocking(c).avoid_weight = Some(number(v)?); Ok(()) } },
+    Flag { long: "avoid-radius", short: None, value: Some("DIST"), help: "Distance obstacles are avoided within", set: |c, v| { flocking(c).avoid_radius = Some(number(v)?); Ok(()) } },
+    Flag { long: "boundary", short: None, value: Some("MODE"), help: "\"wrap\", \"bounce\", \"soft_walls\" or \"open\"", set: |c, v| { flocking(c).boundary = Some(named(v)?); Ok(()) } },
+    Flag { long: "boundary-margin", short: None, value: Some("DIST"), help: "Distance soft walls push back within", set: |c, v| { flocking(c).boundary_margin = Some(number(v)?); Ok(()) } },
+    Flag { long: "neighbours", short: None, value: Some("SEARCH"), help: "\"grid\" or \"exact\" neighbour search", set: |c, v| { flocking(c).neighbours = Some(named(v)?); Ok(()) } },
+    Flag { long: "sort", short: None, value: Some("MODE"), help: "\"full\", \"last\" or \"adaptive\" sorting each step", set: |c, v| { flocking(c).sort = Some(named(v)?); Ok(()) } },
+    Flag { long: "sort-passes", short: None, value: Some("N"), help: "Sort passes made by the \"last\" sort", set: |c, v| { flocking(c).sort_passes = Some(number(v)?); Ok(()) } },
+    Flag { long: "sort-target", short: None, value: Some("SHARE"), help: "Sortedness the \"adaptive\" sort aims for", set: |c, v| { flocking(c).sort_target = Some(number(v)?); Ok(()) } },
+    Flag { long: "kernel", short: None, value: Some("KERNEL"), help: "\"forward\", \"square3\", \"square5\", \"cone\" or \"sample\"", set: |c, v| { flocking(c).kernel = Some(named(v)?); Ok(()) } },
+    Flag { long: "kernel-samples", short: None, value: Some("N"), help: "Cells a sampling kernel looks in", set: |c, v| { flocking(c).kernel_samples = Some(number(v)?); Ok(()) } },
+    Flag { long: "fov", short: None, value: Some("DEGREES"), help: "Field of view of each boid", set: |c, v| { flocking(c).fov = Some(number(v)?); Ok(()) } },
+    Flag { long: "force-fraction", short: None, value: Some("SHARE"), help: "Share of boids working out their forces each step", set: |c, v| { flocking(c).force_update_fraction = Some(number(v)?); Ok(()) } },
+    Flag { long: "force-partition", short: None, value: Some("PARTITION"), help: "\"rows\" or \"cells\" updating their forces each step", set: |c, v| { flocking(c).force_partition = Some(named(v)?); Ok(()) } },
+    Flag { long: "seed", short: None, value: Some("N"), help: "Seed for the random number generator", set: |c, v| { flocking(c).seed = Some(number(v)?); Ok(()) } },
+    Flag { long: "predators", short: None, value: Some("N"), help: "Number of predators", set: |c, v| { predators(c).count = Some(number(v)?); Ok(()) } },
+    Flag { long: "predator-speed", short: None, value: Some("SPEED"), help: "Speed of predators", set: |c, v| { predators(c).max_speed = Some(number(v)?); Ok(()) } },
+    Flag { long: "predator-turn-rate", short: None, value: Some("RATE"), help: "Turn rate of predators", set: |c, v| { predators(c).turn_rate = Some(number(v)?); Ok(()) } },
+    Flag { long: "predator-strategy", short: None, value: Some("STRATEGY"), help: "\"nearest\" or \"densest\"", set: |c, v| { predators(c).strategy = Some(named(v)?); Ok(()) } },
+    Flag { long: "flee-radius", short: None, value: Some("DIST"), help: "Distance predators are fled within", set: |c, v| { predators(c).flee_radius = Some(number(v)?); Ok(()) } },
+    Flag { long: "flee-weight", short: None, value: Some("WEIGHT"), help: "Weight of fleeing", set: |c, v| { predators(c).flee_weight = Some(number(v)?); Ok(()) } },
+    Flag { long: "record", short: None, value: Some("FILE"), help: "Record the run to a file", set: |c, v| { recording(c).record = Some(v.to_owned()); Ok(()) } },
+    Flag { long: "replay", short: None, value: Some("FILE"), help: "Replay a recorded run", set: |c, v| { recording(c).replay = Some(v.to_owned()); Ok(()) } },
+    Flag { long: "steps", short: None, value: Some("N"), help: "Steps to run headless for", set: |c, v| { headless(c).steps = Some(number(v)?); Ok(()) } },
+    Flag { long: "stats-interval", short: None, value: Some("N"), help: "Steps between headless statistics", set: |c, v| { headless(c).stats_interval = Some(number(v)?); Ok(()) } },
+    Flag { long: "stats-output", short: None, value: Some("FILE"), help: "CSV file headless statistics go to", set: |c, v| { headless(c).output = Some(v.to_owned()); Ok(()) } },
+];
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Args, ConfigError> {
     let mut args = args.into_iter();
-    let exec = args.next();
-    match (args.next(), args.next()) {
-        (Some(arg), None) => arg,
-        _ => {
-            let exec = exec.as_deref().unwrap_or("boids");
-            println!("Usage: {exec} config-file");
-            process::exit(1);
+    let mut config_path = None;
+    let mut overrides = UserSimulationConfig::default();
+
+    while let Some(arg) = args.next() {
+        // Flags can be given their value as `--flag=value`, `-f=value` or `--flag value`
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with('-') => (name, Some(value.to_owned())),
+            _ => (arg.as_str(), None),
+        };
+        let flag = if let Some(long) = name.strip_prefix("--") {
+            FLAGS.iter().find(|f| f.long == long)
+        } else if let Some(short) = name.strip_prefix('-').filter(|s| s.chars().count() == 1) {
+            FLAGS
+                .iter()
+                .find(|f| f.short.map(String::from).as_deref() == Some(short))
+        } else if !name.starts_with('-') {
+            // A lone config path is still accepted, as it always has been
+            set_config_path(&mut config_path, arg.clone())?;
+            continue;
+        } else {
+            None
+        };
+        let flag = flag.ok_or_else(|| ConfigError::Args(format!("unknown argument '{}'", arg)))?;
+
+        let value = match (flag.value, inline_value) {
+            (Some(_), Some(value)) => value,
+            (Some(_), None) => args
+                .next()
+                .ok_or_else(|| ConfigError::Args(format!("--{} needs a value", flag.long)))?,
+            (None, Some(_)) => {
+                return Err(ConfigError::Args(format!(
+                    "--{} does not take a value",
+                    flag.long
+                )))
+            }
+            (None, None) => String::new(),
+        };
+
+        match flag.long {
+            HELP_FLAG => return Ok(Args::Help),
+            VERSION_FLAG => return Ok(Args::Version),
+            CONFIG_FLAG => set_config_path(&mut config_path, value)?,
+            _ => (flag.set)(&mut overrides, &value)
+                .map_err(|err| ConfigError::Args(format!("--{}: {}", flag.long, err)))?,
         }
     }
+
+    Ok(Args::Run {
+        config_path,
+        overrides: Box::new(overrides),
+    })
+}
+
+// Whether given alone or with a flag, a second config file would quietly replace the first
+fn set_config_path(config_path: &mut Option<String>, path: String) -> Result<(), ConfigError> {
+    if let Some(ref first) = *config_path {
+        return Err(ConfigError::Args(format!(
+            "only one config file can be given, got '{}' and '{}'",
+            first, path
+        )));
+    }
+    *config_path = Some(path);
+    Ok(())
+}
+
+fn print_usage(exec: &str) {
+    println!("Usage: {} [CONFIG-FILE] [OPTIONS]", exec);
+    println!();
+    println!("Options given here override those in the config file.");
+    println!();
+    for flag in FLAGS {
+        let short = flag
+            .short
+            .map_or(String::from("   "), |s| format!("-{},", s));
+        let long = match flag.value {
+            Some(value) => format!("--{} <{}>", flag.long, value),
+            None => format!("--{}", flag.long),
+        };
+        println!("  {} {:<28} {}", short, long, flag.help);
+    }
 }
 
 struct ConfigBuilder {
     config: SimulationConfig,
+    // Resolved at build time, once all the flocking parameters they inherit are known
+    species: Option<Vec<UserSpeciesConfig>>,
 }
 
 impl ConfigBuilder {
     fn new() -> Self {
         ConfigBuilder {
             config: SimulationConfig::default(),
+            species: None,
         }
     }
 
@@ -61,7 +385,43 @@ impl ConfigBuilder {
         merge(&mut c.boid_count, uc.boid_count);
         merge(&mut c.debug, uc.debug);
         merge(&mut c.window_size, window_size(uc.window));
+        merge(&mut c.resize, uc.window.and_then(|w| w.resize));
+        merge(&mut c.gl_api, uc.window.and_then(|w| w.gl_api));
         merge(&mut c.boid_size, uc.boid_size);
+        merge(&mut c.threads, uc.threads);
+        merge(&mut c.snapshot_path, uc.snapshot);
+        merge(&mut c.tuned_path, uc.tuned);
+        merge(&mut c.reload, uc.reload);
+        merge(
+            &mut c.obstacles,
+            uc.obstacles
+                .map(|obstacles| obstacles.into_iter().map(obstacle).collect()),
+        );
+        merge(&mut self.species, uc.species.map(Some));
+        if let Some(uc_interactions) = uc.interactions {
+            let i = &mut c.interactions;
+            merge(&mut i.separation, uc_interactions.separation);
+            merge(&mut i.alignment, uc_interactions.alignment);
+            merge(&mut i.cohesion, uc_interactions.cohesion);
+        }
+        if let Some(uc_predators) = uc.predators {
+            let p = &mut c.predators;
+            merge(&mut p.count, uc_predators.count);
+            merge(&mut p.max_speed, uc_predators.max_speed);
+            merge(&mut p.turn_rate, uc_predators.turn_rate);
+            merge(&mut p.strategy, uc_predators.strategy);
+            merge(&mut p.flee_radius, uc_predators.flee_radius);
+            merge(&mut p.flee_weight, uc_predators.flee_weight);
+        }
+        if let Some(uc_recording) = uc.recording {
+            merge(&mut c.record_path, uc_recording.record.map(Some));
+            merge(&mut c.replay_path, uc_recording.replay.map(Some));
+        }
+        if let Some(uc_headless) = uc.headless {
+            merge(&mut c.headless.steps, uc_headless.steps);
+            merge(&mut c.headless.stats_interval, uc_headless.stats_interval);
+            merge(&mut c.headless.output, uc_headless.output.map(Some));
+        }
         if let Some(uc_flock) = uc.flocking {
             merge(&mut c.max_speed, uc_flock.max_speed);
             merge(&mut c.max_force, uc_flock.max_force);
@@ -72,11 +432,43 @@ impl ConfigBuilder {
             merge(&mut c.sep_radius, uc_flock.sep_radius);
             merge(&mut c.ali_radius, uc_flock.ali_radius);
             merge(&mut c.coh_radius, uc_flock.coh_radius);
+            merge(&mut c.avoid_weight, uc_flock.avoid_weight);
+            merge(&mut c.avoid_radius, uc_flock.avoid_radius);
+            merge(&mut c.boundary, uc_flock.boundary);
+            merge(&mut c.boundary_margin, uc_flock.boundary_margin);
+            merge(&mut c.neighbours, uc_flock.neighbours);
+            merge(&mut c.sort, uc_flock.sort);
+            merge(&mut c.sort_passes, uc_flock.sort_passes);
+            merge(&mut c.sort_target, uc_flock.sort_target);
+            merge(&mut c.kernel, uc_flock.kernel);
+            merge(&mut c.kernel_samples, uc_flock.kernel_samples);
+            merge(&mut c.fov, uc_flock.fov);
+            merge(&mut c.force_update_fraction, uc_flock.force_update_fraction);
+            merge(&mut c.force_partition, uc_flock.force_partition);
+            merge(&mut c.seed, uc_flock.seed.map(Some));
         }
     }
 
-    fn build(self) -> SimulationConfig {
-        self.config
+    fn build(self) -> Result<SimulationConfig, ConfigError> {
+        let mut c = self.config;
+        if let Some(uc_species) = self.species {
+            let base = &c.species()[0];
+            c.species = uc_species
+                .into_iter()
+                .enumerate()
+                .map(|(i, s)| species(base, i, s))
+                .collect();
+        }
+
+        let validation = validate::validate(&c);
+        for warning in &validation.warnings {
+            println!("Warning: {}", warning);
+        }
+        if validation.errors.is_empty() {
+            Ok(c)
+        } else {
+            Err(ConfigError::Invalid(validation.errors))
+        }
     }
 }
 
@@ -84,6 +476,8 @@ impl ConfigBuilder {
 pub enum ConfigError {
     Io(io::Error),
     Toml(toml::de::Error),
+    Args(String),
+    Invalid(Vec<InvalidField>),
 }
 
 impl From<io::Error> for ConfigError {
@@ -103,6 +497,14 @@ impl fmt::Display for ConfigError {
         match *self {
             ConfigError::Io(ref err) => write!(f, "Could not read config: {}", err),
             ConfigError::Toml(ref err) => write!(f, "Could not parse toml: {}", err),
+            ConfigError::Args(ref err) => write!(f, "Bad command line, {}, see --help", err),
+            ConfigError::Invalid(ref fields) => {
+                write!(f, "Invalid config:")?;
+                for field in fields {
+                    write!(f, "\n  {}", field)?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -114,19 +516,32 @@ impl ConfigError {
     }
 }
 
-#[derive(Deserialize, Default)]
+#[derive(Clone, Deserialize, Default)]
 struct UserSimulationConfig {
+    preset: Option<String>,
     boid_count: Option<u32>,
     debug: Option<bool>,
     window: Option<UserWindowConfig>,
     flocking: Option<UserFlockingConfig>,
     boid_size: Option<f32>,
+    threads: Option<usize>,
+    headless: Option<UserHeadlessConfig>,
+    snapshot: Option<String>,
+    tuned: Option<String>,
+    reload: Option<bool>,
+    recording: Option<UserRecordingConfig>,
+    obstacles: Option<Vec<UserObstacle>>,
+    predators: Option<UserPredatorConfig>,
+    species: Option<Vec<UserSpeciesConfig>>,
+    interactions: Option<UserInteractionsConfig>,
 }
 
 #[derive(Copy, Clone, Deserialize, Default)]
 struct UserWindowConfig {
     size: Option<(u32, u32)>,
     fullscreen: Option<bool>,
+    resize: Option<ResizeMode>,
+    gl_api: Option<GlApi>,
 }
 
 //TODO: Use rename annoations to make these nicer for the user
@@ -141,6 +556,73 @@ struct UserFlockingConfig {
     sep_radius: Option<f32>,
     ali_radius: Option<f32>,
     coh_radius: Option<f32>,
+    avoid_weight: Option<f32>,
+    avoid_radius: Option<f32>,
+    boundary: Option<Boundary>,
+    boundary_margin: Option<f32>,
+    neighbours: Option<NeighbourSearch>,
+    sort: Option<SortMode>,
+    sort_passes: Option<usize>,
+    sort_target: Option<f32>,
+    kernel: Option<NeighbourKernel>,
+    kernel_samples: Option<usize>,
+    fov: Option<f32>,
+    force_update_fraction: Option<f32>,
+    force_partition: Option<ForcePartition>,
+    seed: Option<u64>,
+}
+
+#[derive(Clone, Deserialize, Default)]
+struct UserHeadlessConfig {
+    steps: Option<u64>,
+    stats_interval: Option<u64>,
+    output: Option<String>,
+}
+
+#[derive(Clone, Deserialize, Default)]
+struct UserRecordingConfig {
+    record: Option<String>,
+    replay: Option<String>,
+}
+
+#[derive(Copy, Clone, Deserialize, Default)]
+struct UserPredatorConfig {
+    count: Option<u32>,
+    max_speed: Option<f32>,
+    turn_rate: Option<f32>,
+    strategy: Option<ChaseStrategy>,
+    flee_radius: Option<f32>,
+    flee_weight: Option<f32>,
+}
+
+#[derive(Clone, Deserialize, Default)]
+struct UserSpeciesConfig {
+    name: Option<String>,
+    share: Option<f32>,
+    max_speed: Option<f32>,
+    max_force: Option<f32>,
+    sep_weight: Option<f32>,
+    ali_weight: Option<f32>,
+    coh_weight: Option<f32>,
+    sep_radius: Option<f32>,
+    ali_radius: Option<f32>,
+    coh_radius: Option<f32>,
+    colour: Option<(f32, f32, f32)>,
+}
+
+#[derive(Clone, Deserialize, Default)]
+struct UserInteractionsConfig {
+    separation: Option<Vec<Vec<f32>>>,
+    alignment: Option<Vec<Vec<f32>>>,
+    cohesion: Option<Vec<Vec<f32>>>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(tag = "shape", rename_all = "snake_case")]
+enum UserObstacle {
+    Circle { centre: (f32, f32), radius: f32 },
+    Rect { min: (f32, f32), max: (f32, f32) },
+    Wall { start: (f32, f32), end: (f32, f32) },
 }
 
 impl UserSimulationConfig {
@@ -150,4 +632,118 @@ impl UserSimulationConfig {
         file.read_to_string(&mut contents)?;
         Ok(toml::from_str(&contents)?)
     }
+
+    fn from_preset(name: &str) -> Result<Self, ConfigError> {
+        match PRESETS.iter().find(|&&(preset, _)| preset == name) {
+            Some(&(_, contents)) => Ok(toml::from_str(contents)?),
+            None => {
+                let names: Vec<_> = PRESETS.iter().map(|&(preset, _)| preset).collect();
+                Err(ConfigError::Invalid(vec![InvalidField {
+                    field: String::from("preset"),
+                    problem: format!("must be one of {}, got '{}'", names.join(", "), name),
+                }]))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, ConfigError> {
+        parse_args(args.iter().map(|&arg| String::from(arg)))
+    }
+
+    fn run(args: &[&str]) -> (Option<String>, UserSimulationConfig) {
+        match parse(args) {
+            Ok(Args::Run {
+                config_path,
+                overrides,
+            }) => (config_path, *overrides),
+            Ok(_) => panic!("{:?} did not parse as a run", args),
+            Err(err) => panic!("{:?} did not parse: {}", args, err),
+        }
+    }
+
+    fn args_error(args: &[&str]) -> String {
+        match parse(args) {
+            Err(ConfigError::Args(message)) => message,
+            Err(err) => panic!("{:?} failed with {}", args, err),
+            Ok(_) => panic!("{:?} parsed", args),
+        }
+    }
+
+    #[test]
+    fn flags_set_their_part_of_the_config() {
+        let (path, overrides) = run(&["-n", "500", "--size", "300x200", "-f", "conf.toml"]);
+        assert_eq!(path.as_deref(), Some("conf.toml"));
+        assert_eq!(overrides.boid_count, Some(500));
+        let window = overrides.window.unwrap();
+        assert_eq!(window.size, Some((300, 200)));
+        assert_eq!(window.fullscreen, Some(true));
+    }
+
+    #[test]
+    fn values_can_follow_an_equals_sign() {
+        let (path, overrides) = run(&["--boid-count=500", "-c=conf.toml"]);
+        assert_eq!(path.as_deref(), Some("conf.toml"));
+        assert_eq!(overrides.boid_count, Some(500));
+        let (_, overrides) = run(&["-n=7"]);
+        assert_eq!(overrides.boid_count, Some(7));
+    }
+
+    #[test]
+    fn help_and_version_stop_parsing() {
+        assert!(matches!(parse(&["-h", "--bogus"]), Ok(Args::Help)));
+        assert!(matches!(parse(&["--version"]), Ok(Args::Version)));
+    }
+
+    #[test]
+    fn a_missing_value_is_an_error() {
+        assert!(args_error(&["--boid-count"]).contains("needs a value"));
+        assert!(args_error(&["-c"]).contains("needs a value"));
+    }
+
+    #[test]
+    fn a_switch_given_a_value_is_an_error() {
+        assert!(args_error(&["--fullscreen=yes"]).contains("does not take a value"));
+    }
+
+    #[test]
+    fn an_unknown_flag_is_an_error() {
+        assert!(args_error(&["--bogus"]).contains("unknown argument '--bogus'"));
+        assert!(args_error(&["-x=1"]).contains("unknown argument '-x=1'"));
+    }
+
+    #[test]
+    fn a_bad_value_is_an_error() {
+        assert!(args_error(&["-n", "lots"]).starts_with("--boid-count"));
+    }
+
+    #[test]
+    fn only_one_config_file_can_be_given() {
+        let orders: [&[&str]; 5] = [
+            &["a.toml", "b.toml"],
+            &["a.toml", "-c", "b.toml"],
+            &["-c", "a.toml", "b.toml"],
+            &["-c", "a.toml", "-c", "b.toml"],
+            &["--config=a.toml", "--config", "b.toml"],
+        ];
+        for args in orders {
+            let message = args_error(args);
+            assert!(
+                message.contains("only one config file"),
+                "{:?}: {}",
+                args,
+                message
+            );
+            assert!(
+                message.contains("'a.toml' and 'b.toml'"),
+                "{:?}: {}",
+                args,
+                message
+            );
+        }
+    }
 }
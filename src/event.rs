@@ -1,6 +1,6 @@
-use winit::dpi::PhysicalPosition;
 use winit::event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent};
 
+#[derive(Clone, Serialize, Deserialize)]
 pub enum BoidControlEvent {
     Stop,
     Pause,
@@ -8,15 +8,15 @@ pub enum BoidControlEvent {
     MouseMove(f32, f32),
     MousePress,
     MouseRelease,
+    Resize(u32, u32),
 }
 
-pub struct EventFilter {
-    hidpi_factor: f64,
-}
+// Cursor positions are already in physical pixels, the same units as the world
+pub struct EventFilter;
 
 impl EventFilter {
-    pub fn new(hidpi_factor: f64) -> Self {
-        EventFilter { hidpi_factor }
+    pub fn new() -> Self {
+        EventFilter
     }
 
     pub fn process<T>(&self, event: Event<T>) -> Option<BoidControlEvent> {
@@ -56,6 +56,11 @@ impl EventFilter {
                 ..
             } => Some(BoidControlEvent::MouseRelease),
 
+            // Minimised windows have nothing to draw into, keep the world as it was
+            WindowEvent::Resized(size) if size.width > 0 && size.height > 0 => {
+                Some(BoidControlEvent::Resize(size.width, size.height))
+            }
+
             WindowEvent::CloseRequested => Some(BoidControlEvent::Stop),
             _ => None,
         }
@@ -0,0 +1,76 @@
+// Spreads independent pieces of work across a thread pool when built with the
+// `parallel` feature, otherwise runs them in order on the calling thread.
+
+#[cfg(feature = "parallel")]
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
+
+pub struct Executor {
+    threads: usize,
+    // None if the pool could not be started, in which case work runs on the calling thread
+    #[cfg(feature = "parallel")]
+    pool: Option<ThreadPool>,
+}
+
+impl Executor {
+    /// A thread count of zero picks one thread per core. Falls back to running on the calling
+    /// thread, with a warning, if no threads can be started.
+    #[cfg(feature = "parallel")]
+    pub fn new(threads: usize) -> Executor {
+        let pool = match ThreadPoolBuilder::new().num_threads(threads).build() {
+            Ok(pool) => Some(pool),
+            Err(err) => {
+                println!(
+                    "Warning: Could not start thread pool, running on one thread, {}",
+                    err
+                );
+                None
+            }
+        };
+        Executor { threads, pool }
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    pub fn new(threads: usize) -> Executor {
+        Executor { threads }
+    }
+
+    /// The thread count asked for, zero if it was left to the number of cores.
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    pub fn for_each_index<F>(&self, count: usize, f: F)
+    where
+        F: Fn(usize) + Send + Sync,
+    {
+        #[cfg(feature = "parallel")]
+        if let Some(pool) = &self.pool {
+            pool.install(|| (0..count).into_par_iter().for_each(f));
+            return;
+        }
+
+        (0..count).for_each(f);
+    }
+
+    pub fn for_each_chunk<T, F>(&self, items: &mut [T], chunk_size: usize, f: F)
+    where
+        T: Send,
+        F: Fn(usize, &mut [T]) + Send + Sync,
+    {
+        #[cfg(feature = "parallel")]
+        if let Some(pool) = &self.pool {
+            pool.install(|| {
+                items
+                    .par_chunks_mut(chunk_size)
+                    .enumerate()
+                    .for_each(|(i, chunk)| f(i, chunk))
+            });
+            return;
+        }
+
+        items
+            .chunks_mut(chunk_size)
+            .enumerate()
+            .for_each(|(i, chunk)| f(i, chunk));
+    }
+}
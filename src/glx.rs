@@ -207,15 +207,15 @@ unsafe fn compile_shader(src: &str, shader_type: GLenum) -> Result<GLuint, Shade
     if status != (gl::TRUE as GLint) {
         let mut len = 0;
         gl::GetShaderiv(shader, gl::INFO_LOG_LENGTH, &mut len);
-        let mut buf = Vec::with_capacity(len as usize);
-        // subract 1 to skip the trailing null character
-        buf.set_len((len as usize) - 1);
+        let mut buf = vec![0u8; len.max(1) as usize];
         gl::GetShaderInfoLog(
             shader,
             len,
             ptr::null_mut(),
             buf.as_mut_ptr() as *mut GLchar,
         );
+        // Drop the trailing null character
+        buf.pop();
 
         let err_msg = String::from_utf8(buf).expect("ProgramInfoLog not valid utf8");
         Err(ShaderError::Compilation(err_msg))
@@ -241,15 +241,15 @@ unsafe fn link_program(vrtx_shader: GLuint, frag_shader: GLuint) -> Result<GLuin
     if status != (gl::TRUE as GLint) {
         let mut len = 0;
         gl::GetProgramiv(program, gl::INFO_LOG_LENGTH, &mut len);
-        let mut buf = Vec::with_capacity(len as usize);
-        // subract 1 to skip the trailing null character
-        buf.set_len((len as usize) - 1);
+        let mut buf = vec![0u8; len.max(1) as usize];
         gl::GetProgramInfoLog(
             program,
             len,
             ptr::null_mut(),
             buf.as_mut_ptr() as *mut GLchar,
         );
+        // Drop the trailing null character
+        buf.pop();
 
         let err_msg = String::from_utf8(buf).expect("ProgramInfoLog not valid utf8");
         Err(ShaderError::Linking(err_msg))
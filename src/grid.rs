@@ -0,0 +1,186 @@
+// The neighbour grid, with each field of its boids kept in an array of its own. Forces and
+// neighbour searches only touch the fields they need, and positions, velocities and species
+// can go to the renderer as they are.
+
+use cgmath::{Point2, Vector2};
+
+type Position = Point2<f32>;
+type Velocity = Vector2<f32>;
+type Force = Vector2<f32>;
+
+/// A boid, as copied out of the grid.
+#[derive(Clone, Copy)]
+pub struct Boid {
+    pub(crate) position: Position,
+    pub(crate) velocity: Velocity,
+    pub(crate) species: u32,
+    // Last worked out, kept with the boid as the grid is sorted
+    pub(crate) force: Force,
+}
+
+impl Boid {
+    pub(crate) fn new(species: u32) -> Self {
+        Boid {
+            position: Position::new(0., 0.),
+            velocity: Velocity::new(0., 0.),
+            species,
+            force: Force::new(0., 0.),
+        }
+    }
+
+    // Sentinels pad out the cells of the grid that have no boid in them. Sitting at
+    // infinity they all sort into one corner, and are never within reach of a real boid.
+    pub(crate) fn sentinel() -> Self {
+        Boid {
+            position: Position::new(f32::INFINITY, f32::INFINITY),
+            ..Boid::new(0)
+        }
+    }
+
+    pub fn position(&self) -> Point2<f32> {
+        self.position
+    }
+
+    pub fn velocity(&self) -> Vector2<f32> {
+        self.velocity
+    }
+
+    /// Index into the species the flock was configured with.
+    pub fn species(&self) -> u32 {
+        self.species
+    }
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub(crate) struct BoidGrid {
+    pub(crate) positions: Vec<Position>,
+    pub(crate) velocities: Vec<Velocity>,
+    pub(crate) species: Vec<u32>,
+    pub(crate) forces: Vec<Force>,
+}
+
+impl BoidGrid {
+    pub(crate) fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    // Every field should have an entry for every cell
+    pub(crate) fn is_consistent(&self) -> bool {
+        let len = self.len();
+        self.velocities.len() == len && self.species.len() == len && self.forces.len() == len
+    }
+
+    pub(crate) fn is_sentinel(&self, index: usize) -> bool {
+        self.positions[index].x.is_infinite()
+    }
+
+    pub(crate) fn get(&self, index: usize) -> Boid {
+        Boid {
+            position: self.positions[index],
+            velocity: self.velocities[index],
+            species: self.species[index],
+            force: self.forces[index],
+        }
+    }
+
+    pub(crate) fn push(&mut self, boid: Boid) {
+        self.positions.push(boid.position);
+        self.velocities.push(boid.velocity);
+        self.species.push(boid.species);
+        self.forces.push(boid.force);
+    }
+
+    /// Indexes of the cells holding real boids.
+    pub(crate) fn boid_indexes(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len()).filter(move |&i| !self.is_sentinel(i))
+    }
+
+    /// Positions of the real boids, to move them about.
+    pub(crate) fn boid_positions_mut(&mut self) -> impl Iterator<Item = &mut Position> {
+        self.positions.iter_mut().filter(|p| !p.x.is_infinite())
+    }
+
+    /// Pack the real boids into the first cells, and pad the rest of `len` cells with sentinels.
+    pub(crate) fn repack(&mut self, len: usize) {
+        let mut packed = BoidGrid::default();
+        for index in self.boid_indexes() {
+            packed.push(self.get(index));
+        }
+        while packed.len() < len {
+            packed.push(Boid::sentinel());
+        }
+        *self = packed;
+    }
+
+    pub(crate) fn ptr(&mut self) -> GridPtr {
+        GridPtr {
+            positions: self.positions.as_mut_ptr(),
+            velocities: self.velocities.as_mut_ptr(),
+            species: self.species.as_mut_ptr(),
+            forces: self.forces.as_mut_ptr(),
+        }
+    }
+}
+
+impl FromIterator<Boid> for BoidGrid {
+    fn from_iter<I: IntoIterator<Item = Boid>>(boids: I) -> Self {
+        let mut grid = BoidGrid::default();
+        for boid in boids {
+            grid.push(boid);
+        }
+        grid
+    }
+}
+
+// Lets the sorting and update passes hand out disjoint rows or columns of the grid to each thread
+#[derive(Clone, Copy)]
+pub(crate) struct GridPtr {
+    positions: *mut Position,
+    velocities: *mut Velocity,
+    species: *mut u32,
+    forces: *mut Force,
+}
+
+unsafe impl Send for GridPtr {}
+unsafe impl Sync for GridPtr {}
+
+// The fields of a run of cells, borrowed from the grid
+pub(crate) struct GridSlice<'a> {
+    pub(crate) positions: &'a mut [Position],
+    pub(crate) velocities: &'a mut [Velocity],
+    pub(crate) species: &'a [u32],
+    pub(crate) forces: &'a mut [Force],
+}
+
+impl GridPtr {
+    pub(crate) unsafe fn read(self, index: usize) -> Boid {
+        Boid {
+            position: *self.positions.add(index),
+            velocity: *self.velocities.add(index),
+            species: *self.species.add(index),
+            force: *self.forces.add(index),
+        }
+    }
+
+    pub(crate) unsafe fn write(self, index: usize, boid: Boid) {
+        *self.positions.add(index) = boid.position;
+        *self.velocities.add(index) = boid.velocity;
+        *self.species.add(index) = boid.species;
+        *self.forces.add(index) = boid.force;
+    }
+
+    pub(crate) unsafe fn position(self, index: usize) -> Position {
+        *self.positions.add(index)
+    }
+
+    // No other slice of the cells may be alive at the same time
+    pub(crate) unsafe fn slice<'a>(self, start: usize, len: usize) -> GridSlice<'a> {
+        use std::slice::{from_raw_parts, from_raw_parts_mut};
+        GridSlice {
+            positions: from_raw_parts_mut(self.positions.add(start), len),
+            velocities: from_raw_parts_mut(self.velocities.add(start), len),
+            species: from_raw_parts(self.species.add(start), len),
+            forces: from_raw_parts_mut(self.forces.add(start), len),
+        }
+    }
+}
@@ -0,0 +1,352 @@
+use std::error::{self, Error};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::time::Instant;
+use std::{fmt, process};
+
+use cgmath::{InnerSpace, Vector2};
+
+use crate::boids::{
+    apply_event, build_flocking_config, SimulationConfig, WindowSize, STEPS_PER_SECOND,
+};
+use crate::grid::Boid;
+use crate::record::{RecordingError, Replay};
+use crate::system::{FlockingSystem, NeighbourComparison};
+
+#[derive(Debug)]
+pub enum HeadlessError {
+    NoWorldSize,
+    Io(io::Error),
+    Recording(RecordingError),
+}
+
+impl From<io::Error> for HeadlessError {
+    fn from(err: io::Error) -> HeadlessError {
+        HeadlessError::Io(err)
+    }
+}
+
+impl From<RecordingError> for HeadlessError {
+    fn from(err: RecordingError) -> HeadlessError {
+        HeadlessError::Recording(err)
+    }
+}
+
+impl fmt::Display for HeadlessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HeadlessError::NoWorldSize => {
+                write!(
+                    f,
+                    "A window size is needed to size the world, not fullscreen"
+                )
+            }
+            HeadlessError::Io(ref err) => write!(f, "Could not write output, {}", err),
+            HeadlessError::Recording(ref err) => write!(f, "Could not replay, {}", err),
+        }
+    }
+}
+
+impl error::Error for HeadlessError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            HeadlessError::NoWorldSize => None,
+            HeadlessError::Io(ref err) => Some(err),
+            HeadlessError::Recording(ref err) => Some(err),
+        }
+    }
+}
+
+impl HeadlessError {
+    pub fn exit(&self) -> ! {
+        println!("{}", self);
+        process::exit(1);
+    }
+}
+
+// Where each binary writes unless told otherwise, kept apart so one does not replace the other
+const STATS_OUTPUT: &str = "flock-stats.csv";
+const COMPARISON_OUTPUT: &str = "neighbour-comparison.csv";
+
+pub struct HeadlessConfig {
+    pub steps: u64,
+    pub stats_interval: u64,
+    pub output: Option<String>,
+}
+
+impl Default for HeadlessConfig {
+    fn default() -> HeadlessConfig {
+        HeadlessConfig {
+            steps: 3600,
+            stats_interval: 60,
+            output: None,
+        }
+    }
+}
+
+/// Run the simulation without a window, writing statistics about the flock as it goes.
+pub fn run_headless(sim_config: SimulationConfig) -> Result<(), HeadlessError> {
+    let conf = &sim_config.headless;
+    let dt = 1. / STEPS_PER_SECOND as f32;
+    let (mut simulation, mut replay) = start(&sim_config)?;
+
+    let output = conf.output.as_deref().unwrap_or(STATS_OUTPUT);
+    let mut out = BufWriter::new(File::create(output)?);
+    writeln!(out, "{}", FlockStats::CSV_HEADER)?;
+
+    let started = Instant::now();
+    for step in 0..=conf.steps {
+        if conf.stats_interval > 0 && step % conf.stats_interval == 0 {
+            let stats = FlockStats::measure(&simulation);
+            writeln!(out, "{},{:.3},{}", step, step as f32 * dt, stats.to_csv())?;
+        }
+        if step < conf.steps {
+            if let Some(ref mut r) = replay {
+                r.apply_due(&mut simulation, apply_event)?;
+            }
+            simulation.update(dt);
+        }
+    }
+    out.flush()?;
+
+    let elapsed = started.elapsed().as_secs_f32();
+    println!(
+        "Ran {} steps of {} boids in {:.2}s ({:.1} steps per second), wrote {}",
+        conf.steps,
+        simulation.boid_count(),
+        elapsed,
+        conf.steps as f32 / elapsed,
+        output
+    );
+    Ok(())
+}
+
+/// Run the simulation without a window, measuring how far the neighbour grid is from exact.
+///
+/// The flock moves according to the configured neighbour search, the comparison is made
+/// every stats interval and written out in place of the usual statistics.
+pub fn run_comparison(sim_config: SimulationConfig) -> Result<(), HeadlessError> {
+    let conf = &sim_config.headless;
+    let dt = 1. / STEPS_PER_SECOND as f32;
+    let (mut simulation, mut replay) = start(&sim_config)?;
+
+    let output = conf.output.as_deref().unwrap_or(COMPARISON_OUTPUT);
+    let mut out = BufWriter::new(File::create(output)?);
+    writeln!(
+        out,
+        "step,time,boids,recall,precision,mean_force_error,relative_force_error"
+    )?;
+
+    let mut total = NeighbourComparison::default();
+    for step in 0..=conf.steps {
+        if conf.stats_interval > 0 && step % conf.stats_interval == 0 {
+            let c = simulation.compare_neighbours(dt);
+            writeln!(
+                out,
+                "{},{:.3},{},{:.4},{:.4},{:.4},{:.4}",
+                step,
+                step as f32 * dt,
+                c.boids,
+                c.recall(),
+                c.precision(),
+                c.mean_force_error(),
+                c.relative_force_error()
+            )?;
+            total.add(&c);
+        }
+        if step < conf.steps {
+            if let Some(ref mut r) = replay {
+                r.apply_due(&mut simulation, apply_event)?;
+            }
+            simulation.update(dt);
+        }
+    }
+    out.flush()?;
+
+    println!(
+        "Over {} steps the neighbour grid found {:.1}% of neighbours, {:.1}% of the boids it \
+         looked at were neighbours, and forces were off by {:.1}% on average. Wrote {}",
+        conf.steps,
+        total.recall() * 100.,
+        total.precision() * 100.,
+        total.relative_force_error() * 100.,
+        output
+    );
+    Ok(())
+}
+
+fn start(sim_config: &SimulationConfig) -> Result<(FlockingSystem, Option<Replay>), HeadlessError> {
+    let (width, height) = match sim_config.window_size {
+        WindowSize::Dimensions((w, h)) => (w as f32, h as f32),
+        WindowSize::Fullscreen => return Err(HeadlessError::NoWorldSize),
+    };
+    let mut simulation = FlockingSystem::new(build_flocking_config(sim_config, width, height));
+    println!("Flock seed: {}", simulation.seed());
+    simulation.randomise();
+    let replay = match sim_config.replay_path {
+        Some(ref path) => Some(Replay::open(path)?),
+        None => None,
+    };
+    Ok((simulation, replay))
+}
+
+struct FlockStats {
+    mean_speed: f32,
+    // How aligned the flock is, 1 when all boids head the same way, 0 when directions cancel out
+    polarisation: f32,
+    centroid: Vector2<f32>,
+    // Root mean square distance of boids from the centroid
+    spread: f32,
+    sortedness: f32,
+    sort_passes: usize,
+}
+
+impl FlockStats {
+    const CSV_HEADER: &'static str =
+        "step,time,mean_speed,polarisation,centroid_x,centroid_y,spread,sortedness,sort_passes";
+
+    fn measure(simulation: &FlockingSystem) -> FlockStats {
+        let boids: Vec<Boid> = simulation.boids().collect();
+        FlockStats::of(&boids, simulation.sortedness(), simulation.sort_passes())
+    }
+
+    fn of(boids: &[Boid], sortedness: f32, sort_passes: usize) -> FlockStats {
+        let count = boids.len().max(1) as f32;
+        let mut speed_sum = 0.;
+        let mut heading_sum = Vector2::new(0., 0.);
+        let mut position_sum = Vector2::new(0., 0.);
+        for boid in boids {
+            let speed = boid.velocity().magnitude();
+            speed_sum += speed;
+            if speed > 0. {
+                heading_sum += boid.velocity() / speed;
+            }
+            position_sum += Vector2::new(boid.position().x, boid.position().y);
+        }
+        let centroid = position_sum / count;
+        let spread_sq = boids
+            .iter()
+            .map(|b| (Vector2::new(b.position().x, b.position().y) - centroid).magnitude2())
+            .sum::<f32>()
+            / count;
+
+        FlockStats {
+            mean_speed: speed_sum / count,
+            polarisation: heading_sum.magnitude() / count,
+            centroid,
+            spread: spread_sq.sqrt(),
+            sortedness,
+            sort_passes,
+        }
+    }
+
+    fn to_csv(&self) -> String {
+        format!(
+            "{:.4},{:.4},{:.2},{:.2},{:.2},{:.4},{}",
+            self.mean_speed,
+            self.polarisation,
+            self.centroid.x,
+            self.centroid.y,
+            self.spread,
+            self.sortedness,
+            self.sort_passes
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use cgmath::Point2;
+
+    use super::*;
+
+    fn boid(x: f32, y: f32, vx: f32, vy: f32) -> Boid {
+        Boid {
+            position: Point2::new(x, y),
+            velocity: Vector2::new(vx, vy),
+            ..Boid::new(0)
+        }
+    }
+
+    fn stats(boids: &[Boid]) -> FlockStats {
+        FlockStats::of(boids, 1., 1)
+    }
+
+    #[test]
+    fn a_flock_heading_the_same_way_is_fully_polarised() {
+        let boids = [
+            boid(0., 0., 3., 4.),
+            boid(10., 5., 6., 8.),
+            boid(-4., 2., 0.3, 0.4),
+        ];
+        assert!((stats(&boids).polarisation - 1.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn opposed_pairs_cancel_out() {
+        let boids = [
+            boid(0., 0., 1., 0.),
+            boid(5., 5., -2., 0.),
+            boid(1., 3., 0., 3.),
+            boid(2., 8., 0., -1.),
+        ];
+        assert!(stats(&boids).polarisation.abs() < 1e-6);
+    }
+
+    #[test]
+    fn speed_centroid_and_spread_are_averaged_over_the_flock() {
+        let boids = [boid(0., 0., 3., 4.), boid(2., 0., 0., 1.)];
+        let s = stats(&boids);
+        assert_eq!(s.mean_speed, 3.);
+        assert_eq!(s.centroid, Vector2::new(1., 0.));
+        assert_eq!(s.spread, 1.);
+        assert_eq!(stats(&[]).mean_speed, 0.);
+    }
+
+    // Ten steps of a small flock, with statistics every five, read back as CSV lines
+    fn run(name: &str, run: fn(SimulationConfig) -> Result<(), HeadlessError>) -> Vec<String> {
+        let path = env::temp_dir().join(format!("{}-{}.csv", name, process::id()));
+        let sim_config = SimulationConfig {
+            boid_count: 50,
+            window_size: WindowSize::Dimensions((100, 100)),
+            headless: HeadlessConfig {
+                steps: 10,
+                stats_interval: 5,
+                output: Some(path.to_str().unwrap().to_owned()),
+            },
+            ..SimulationConfig::default()
+        };
+        run(sim_config).unwrap();
+        let csv = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        csv.lines().map(String::from).collect()
+    }
+
+    fn steps(lines: &[String]) -> Vec<&str> {
+        lines[1..]
+            .iter()
+            .map(|l| l.split(',').next().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn a_headless_run_writes_a_row_every_stats_interval() {
+        let lines = run("headless", run_headless);
+        assert_eq!(lines[0], FlockStats::CSV_HEADER);
+        assert_eq!(steps(&lines), ["0", "5", "10"]);
+    }
+
+    #[test]
+    fn a_comparison_writes_a_share_of_neighbours_found_every_stats_interval() {
+        let lines = run("comparison", run_comparison);
+        assert!(lines[0].starts_with("step,time,boids,recall,"));
+        assert_eq!(steps(&lines), ["0", "5", "10"]);
+        for line in &lines[1..] {
+            let recall: f32 = line.split(',').nth(3).unwrap().parse().unwrap();
+            assert!((0. ..=1.).contains(&recall), "{}", line);
+        }
+    }
+}
@@ -0,0 +1,186 @@
+// Which cells of the neighbour grid a boid looks in for its flockmates
+
+use std::f32::consts::PI;
+
+use cgmath::Vector2;
+
+/// The pattern of grid cells, around its own, that a boid searches for neighbours.
+///
+/// Bigger kernels find more of the true neighbours, at the cost of looking at more boids.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NeighbourKernel {
+    /// Ten cells, mostly along the line the boid is heading.
+    ///
+    /// The grid's x and y fall as its columns and rows rise, and these cells, kept from before
+    /// there was a field of view, lie mostly behind the boid. A narrow field of view filters
+    /// most of them out, where `Cone` looks ahead.
+    Forward,
+    /// The eight cells surrounding the boid.
+    Square3,
+    /// The twenty four cells up to two away from the boid.
+    Square5,
+    /// Cells up to three away within a wide cone ahead of the boid, and those right beside it.
+    Cone,
+    /// A different random handful of the cells up to two away, every step.
+    Sample,
+}
+
+impl NeighbourKernel {
+    /// How many cells away from its own the kernel looks, at furthest.
+    pub fn reach(self) -> usize {
+        match self {
+            NeighbourKernel::Square3 => 1,
+            NeighbourKernel::Forward | NeighbourKernel::Square5 | NeighbourKernel::Sample => 2,
+            NeighbourKernel::Cone => CONE_REACH as usize,
+        }
+    }
+}
+
+// The eight surrounding cells and the sixteen around those, the pool samples are taken from
+const SQUARE5_REACH: i32 = 2;
+const CONE_REACH: i32 = 3;
+const CONE_HALF_ANGLE: f32 = 75. * PI / 180.;
+// Cone tables are worked out for this many headings
+const CONE_SECTORS: usize = 16;
+
+/// The most cells a sampling kernel can look in.
+pub const MAX_SAMPLES: usize = 24;
+
+type Offsets = Vec<(i32, i32)>;
+
+/// The cells of a kernel worked out ahead of time, for each heading it cares about.
+pub(crate) struct KernelTables {
+    kernel: NeighbourKernel,
+    tables: Vec<Offsets>,
+    samples: usize,
+}
+
+impl KernelTables {
+    pub(crate) fn new(kernel: NeighbourKernel, samples: usize) -> KernelTables {
+        let tables = match kernel {
+            NeighbourKernel::Forward => forward_tables(),
+            NeighbourKernel::Square3 => vec![square(1)],
+            NeighbourKernel::Square5 | NeighbourKernel::Sample => vec![square(SQUARE5_REACH)],
+            NeighbourKernel::Cone => cone_tables(),
+        };
+        KernelTables {
+            kernel,
+            tables,
+            samples: samples.min(MAX_SAMPLES),
+        }
+    }
+
+    /// Call `f` with each cell offset to look in, for a boid with the given velocity.
+    ///
+    /// The seed picks the cells of a sampling kernel, and should change from step to step.
+    pub(crate) fn for_each_offset<F>(&self, velocity: Vector2<f32>, seed: u64, mut f: F)
+    where
+        F: FnMut(i32, i32),
+    {
+        let table = &self.tables[self.table_index(velocity)];
+        if self.kernel != NeighbourKernel::Sample {
+            for &(x, y) in table {
+                f(x, y);
+            }
+            return;
+        }
+        // A partial shuffle of the pool, stopping once enough cells have been drawn
+        let mut pool = [(0, 0); MAX_SAMPLES];
+        pool.copy_from_slice(table);
+        let mut state = seed;
+        for i in 0..self.samples {
+            state = splitmix64(state);
+            let j = i + (state % (MAX_SAMPLES - i) as u64) as usize;
+            pool.swap(i, j);
+            let (x, y) = pool[i];
+            f(x, y);
+        }
+    }
+
+    fn table_index(&self, v: Vector2<f32>) -> usize {
+        match self.kernel {
+            NeighbourKernel::Forward => {
+                (v.x > 0.) as usize
+                    | ((v.y > 0.) as usize) << 1
+                    | ((v.x.abs() > v.y.abs()) as usize) << 2
+            }
+            NeighbourKernel::Cone => {
+                let sector = 2. * PI / CONE_SECTORS as f32;
+                let angle = v.y.atan2(v.x).rem_euclid(2. * PI);
+                ((angle / sector) as usize).min(CONE_SECTORS - 1)
+            }
+            _ => 0,
+        }
+    }
+}
+
+// This is essentially a look up table to determine which flockmates the boid is facing,
+// indexed by whether it heads towards +x, towards +y, and more along x than y
+#[rustfmt::skip]
+fn forward_tables() -> Vec<Offsets> {
+    vec![
+        // Heading -x, -y, mostly along y
+        vec![(-1, -1), (0, -1), (1, -1), (0, -2), (-1, -2),
+             (-1, 0), (-2, -2), (1, 0), (-2, -1), (1, -2)],
+        // Heading +x, -y, mostly along y
+        vec![(-1, -1), (0, -1), (1, -1), (0, -2), (1, -2),
+             (1, 0), (2, -2), (-1, 0), (-1, -2), (2, -1)],
+        // Heading -x, +y, mostly along y
+        vec![(1, 1), (0, 1), (-1, 1), (0, 2), (-1, 2),
+             (-1, 0), (-2, 2), (1, 0), (1, 2), (-2, 1)],
+        // Heading +x, +y, mostly along y
+        vec![(1, 1), (0, 1), (-1, 1), (0, 2), (1, 2),
+             (1, 0), (2, 2), (-1, 0), (2, 1), (-1, 2)],
+        // Heading -x, -y, mostly along x
+        vec![(-1, 1), (-1, 0), (-1, -1), (-2, 0), (-2, -1),
+             (0, -1), (-2, -2), (0, 1), (-2, 1), (-1, -2)],
+        // Heading +x, -y, mostly along x
+        vec![(1, -1), (1, 0), (1, 1), (2, 0), (2, -1),
+             (0, -1), (2, -2), (0, 1), (1, -2), (2, 1)],
+        // Heading -x, +y, mostly along x
+        vec![(-1, 1), (-1, 0), (-1, -1), (-2, 0), (-2, 1),
+             (0, 1), (-2, 2), (0, -1), (-1, 2), (-2, -1)],
+        // Heading +x, +y, mostly along x
+        vec![(1, -1), (1, 0), (1, 1), (2, 0), (2, 1),
+             (0, 1), (2, 2), (0, -1), (2, -1), (1, 2)],
+    ]
+}
+
+fn square(reach: i32) -> Offsets {
+    let mut offsets = Vec::new();
+    for y in -reach..=reach {
+        for x in -reach..=reach {
+            if (x, y) != (0, 0) {
+                offsets.push((x, y));
+            }
+        }
+    }
+    offsets
+}
+
+fn cone_tables() -> Vec<Offsets> {
+    let sector = 2. * PI / CONE_SECTORS as f32;
+    (0..CONE_SECTORS)
+        .map(|s| {
+            let heading = (s as f32 + 0.5) * sector;
+            square(CONE_REACH)
+                .into_iter()
+                .filter(|&(x, y)| {
+                    // x and y fall as the column and row rise, so the cell lies the other way
+                    let direction = (-y as f32).atan2(-x as f32);
+                    let off_heading = (direction - heading).abs();
+                    let off_heading = off_heading.min(2. * PI - off_heading);
+                    x.abs().max(y.abs()) == 1 || off_heading <= CONE_HALF_ANGLE
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn splitmix64(state: u64) -> u64 {
+    let mut z = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
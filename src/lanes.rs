@@ -0,0 +1,329 @@
+// Neighbours gathered into flat arrays, so reacting to them runs the same sums over several
+// neighbours at once. The lane loops have a fixed size so the compiler can vectorise them.
+
+use cgmath::{Point2, Vector2};
+
+use crate::species::{Interaction, SpeciesConstants};
+
+type Position = Point2<f32>;
+type Velocity = Vector2<f32>;
+
+// How many neighbours are reacted to side by side. Four floats fill the 128 bit registers every
+// x86-64 and AArch64 processor has, so no target features are needed.
+const LANES: usize = 4;
+
+/// The neighbours of one boid at a time, reused from boid to boid to save allocating.
+pub(crate) struct NeighbourLanes {
+    len: usize,
+    sets: Vec<LaneSet>,
+}
+
+// One set of neighbours, side by side. Lanes past the last neighbour sit at infinity, out of
+// reach of the boid.
+#[derive(Clone, Copy)]
+struct LaneSet {
+    x: [f32; LANES],
+    y: [f32; LANES],
+    // Where in the grid each neighbour is, to fill in the rest of the lanes from
+    index: [usize; LANES],
+    vx: [f32; LANES],
+    vy: [f32; LANES],
+    // How strongly the boid reacts to each neighbour, from both their species
+    separation: [f32; LANES],
+    alignment: [f32; LANES],
+    cohesion: [f32; LANES],
+}
+
+impl LaneSet {
+    const EMPTY: LaneSet = LaneSet {
+        x: [f32::INFINITY; LANES],
+        y: [0.; LANES],
+        index: [0; LANES],
+        vx: [0.; LANES],
+        vy: [0.; LANES],
+        separation: [0.; LANES],
+        alignment: [0.; LANES],
+        cohesion: [0.; LANES],
+    };
+}
+
+/// What a boid's neighbours add up to, before steering towards any of it.
+pub(crate) struct NeighbourSums {
+    /// Away from neighbours that are too close, more so the closer they are.
+    pub(crate) dodge: Vector2<f32>,
+    pub(crate) ali_vel: Vector2<f32>,
+    /// Offsets rather than positions, so other species can pull with their own strength.
+    pub(crate) coh_offset: Vector2<f32>,
+}
+
+impl NeighbourLanes {
+    pub(crate) fn new() -> NeighbourLanes {
+        NeighbourLanes {
+            len: 0,
+            sets: Vec::new(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn clear(&mut self) {
+        for set in &mut self.sets[..self.len.div_ceil(LANES)] {
+            set.x = [f32::INFINITY; LANES];
+        }
+        self.len = 0;
+    }
+
+    /// Add the position of the neighbour at `index` in the grid, to be filled in later.
+    pub(crate) fn push(&mut self, index: usize, position: Position) {
+        let (s, l) = (self.len / LANES, self.len % LANES);
+        if s == self.sets.len() {
+            self.sets.push(LaneSet::EMPTY);
+        }
+        let set = &mut self.sets[s];
+        set.x[l] = position.x;
+        set.y[l] = position.y;
+        set.index[l] = index;
+        self.len += 1;
+    }
+
+    /// Drop the neighbours not within `radius_2`, squared, of the position.
+    pub(crate) fn keep_within(&mut self, position: Position, radius_2: f32) {
+        // Done once all are found, so the search itself only copies. Each neighbour is written
+        // out whether kept or not, so there is no branch on a comparison that goes either way.
+        let found = self.len;
+        self.len = 0;
+        for i in 0..found {
+            let set = &self.sets[i / LANES];
+            let (x, y, index) = (set.x[i % LANES], set.y[i % LANES], set.index[i % LANES]);
+            let (dx, dy) = (position.x - x, position.y - y);
+            let keep = dx * dx + dy * dy < radius_2;
+            let set = &mut self.sets[self.len / LANES];
+            set.x[self.len % LANES] = x;
+            set.y[self.len % LANES] = y;
+            set.index[self.len % LANES] = index;
+            self.len += keep as usize;
+        }
+        // The lanes left behind must be out of reach again
+        for i in self.len..found {
+            self.sets[i / LANES].x[i % LANES] = f32::INFINITY;
+        }
+    }
+
+    /// Fill in the velocity of each neighbour kept, and how the boid reacts to it.
+    pub(crate) fn fill<F>(&mut self, mut neighbour: F)
+    where
+        F: FnMut(usize) -> (Velocity, Interaction),
+    {
+        for i in 0..self.len {
+            let (set, l) = (&mut self.sets[i / LANES], i % LANES);
+            let (velocity, with) = neighbour(set.index[l]);
+            set.vx[l] = velocity.x;
+            set.vy[l] = velocity.y;
+            set.separation[l] = with.separation;
+            set.alignment[l] = with.alignment;
+            set.cohesion[l] = with.cohesion;
+        }
+    }
+
+    /// How many of the neighbours are within `radius_2`, squared, of the position.
+    pub(crate) fn count_within(&self, position: Position, radius_2: f32) -> usize {
+        (0..self.len)
+            .filter(|&i| {
+                let set = &self.sets[i / LANES];
+                let (dx, dy) = (position.x - set.x[i % LANES], position.y - set.y[i % LANES]);
+                dx * dx + dy * dy < radius_2
+            })
+            .count()
+    }
+
+    /// Add up the neighbours, as seen by a boid of the given species at `position`.
+    pub(crate) fn sum(&self, position: Position, species: &SpeciesConstants) -> NeighbourSums {
+        // Running totals are kept apart for each lane, and only added together at the end
+        let mut dodge_x = [0f32; LANES];
+        let mut dodge_y = [0f32; LANES];
+        let mut ali_x = [0f32; LANES];
+        let mut ali_y = [0f32; LANES];
+        let mut coh_x = [0f32; LANES];
+        let mut coh_y = [0f32; LANES];
+
+        for set in &self.sets[..self.len.div_ceil(LANES)] {
+            for l in 0..LANES {
+                let dx = position.x - set.x[l];
+                let dy = position.y - set.y[l];
+                let d2 = dx * dx + dy * dy;
+                // Masks rather than branches, a neighbour on top of the boid adds nothing.
+                // Everything is worked out for every lane, so masking has to come last.
+                let near = d2 > 0.;
+                let sep_m = near & (d2 < species.sep_radius_2);
+                let ali_m = near & (d2 < species.ali_radius_2);
+                let coh_m = near & (d2 < species.coh_radius_2);
+                let sep = set.separation[l] / d2;
+                dodge_x[l] += if sep_m { dx * sep } else { 0. };
+                dodge_y[l] += if sep_m { dy * sep } else { 0. };
+                ali_x[l] += if ali_m {
+                    set.vx[l] * set.alignment[l]
+                } else {
+                    0.
+                };
+                ali_y[l] += if ali_m {
+                    set.vy[l] * set.alignment[l]
+                } else {
+                    0.
+                };
+                coh_x[l] -= if coh_m { dx * set.cohesion[l] } else { 0. };
+                coh_y[l] -= if coh_m { dy * set.cohesion[l] } else { 0. };
+            }
+        }
+
+        let total = |lanes: [f32; LANES]| lanes.iter().sum();
+        NeighbourSums {
+            dodge: Vector2::new(total(dodge_x), total(dodge_y)),
+            ali_vel: Vector2::new(total(ali_x), total(ali_y)),
+            coh_offset: Vector2::new(total(coh_x), total(coh_y)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::species::SpeciesConfig;
+    use cgmath::InnerSpace;
+
+    const BOID: Position = Position::new(50., 50.);
+
+    fn species() -> SpeciesConstants {
+        SpeciesConstants::from_config(&SpeciesConfig {
+            name: String::from("boid"),
+            share: 1.,
+            max_speed: 150.,
+            max_force: 1440.,
+            sep_weight: 1.5,
+            ali_weight: 1.,
+            coh_weight: 1.,
+            sep_radius: 4.,
+            ali_radius: 8.,
+            coh_radius: 11.,
+            colour: None,
+        })
+    }
+
+    struct Neighbour {
+        position: Position,
+        velocity: Velocity,
+        with: Interaction,
+    }
+
+    // Scattered in and out of every radius, one on top of the boid and a sentinel among them
+    fn neighbours(count: usize) -> Vec<Neighbour> {
+        (0..count)
+            .map(|i| {
+                let position = match i {
+                    0 => BOID,
+                    3 => Position::new(f32::INFINITY, f32::INFINITY),
+                    _ => BOID + Vector2::new(i as f32 * 1.7 - 6., 9. - i as f32 * 2.3),
+                };
+                Neighbour {
+                    position,
+                    velocity: Vector2::new(i as f32 - 3., 2. * i as f32),
+                    with: Interaction {
+                        separation: 1. + i as f32 * 0.5,
+                        alignment: 1.5 - i as f32 * 0.5,
+                        cohesion: 0.5 + i as f32 * 0.25,
+                    },
+                }
+            })
+            .collect()
+    }
+
+    fn line_up(lanes: &mut NeighbourLanes, neighbours: &[Neighbour]) {
+        for (i, n) in neighbours.iter().enumerate() {
+            lanes.push(i, n.position);
+        }
+        lanes.fill(|i| (neighbours[i].velocity, neighbours[i].with));
+    }
+
+    // One neighbour at a time, as the force used to be worked out before lanes
+    fn scalar_sum(neighbours: &[Neighbour], species: &SpeciesConstants) -> NeighbourSums {
+        let mut sums = NeighbourSums {
+            dodge: Vector2::new(0., 0.),
+            ali_vel: Vector2::new(0., 0.),
+            coh_offset: Vector2::new(0., 0.),
+        };
+        for n in neighbours {
+            let from = BOID - n.position;
+            let d2 = from.magnitude2();
+            if d2 > 0. {
+                if d2 < species.sep_radius_2 {
+                    sums.dodge += from.normalize_to(1. / d2.sqrt()) * n.with.separation;
+                }
+                if d2 < species.ali_radius_2 {
+                    sums.ali_vel += n.velocity * n.with.alignment;
+                }
+                if d2 < species.coh_radius_2 {
+                    sums.coh_offset -= from * n.with.cohesion;
+                }
+            }
+        }
+        sums
+    }
+
+    fn assert_same(lanes: NeighbourSums, scalar: NeighbourSums) {
+        for (a, b) in [
+            (lanes.dodge, scalar.dodge),
+            (lanes.ali_vel, scalar.ali_vel),
+            (lanes.coh_offset, scalar.coh_offset),
+        ] {
+            assert!(
+                (a - b).magnitude() <= 1e-4 * (1. + b.magnitude()),
+                "{:?} != {:?}",
+                a,
+                b
+            );
+        }
+    }
+
+    #[test]
+    fn lanes_add_up_to_the_scalar_sum() {
+        let species = species();
+        for count in [0, 1, 5, 7] {
+            let neighbours = neighbours(count);
+            let mut lanes = NeighbourLanes::new();
+            line_up(&mut lanes, &neighbours);
+            assert_eq!(lanes.len(), count);
+            assert_same(lanes.sum(BOID, &species), scalar_sum(&neighbours, &species));
+        }
+    }
+
+    #[test]
+    fn cleared_lanes_forget_earlier_neighbours() {
+        let species = species();
+        let mut lanes = NeighbourLanes::new();
+        line_up(&mut lanes, &neighbours(7));
+        let neighbours = neighbours(5);
+        lanes.clear();
+        line_up(&mut lanes, &neighbours);
+        assert_same(lanes.sum(BOID, &species), scalar_sum(&neighbours, &species));
+    }
+
+    #[test]
+    fn lanes_keep_only_those_within_reach() {
+        let species = species();
+        let neighbours = neighbours(7);
+        let mut lanes = NeighbourLanes::new();
+        for (i, n) in neighbours.iter().enumerate() {
+            lanes.push(i, n.position);
+        }
+        lanes.keep_within(BOID, species.sep_radius_2);
+        lanes.fill(|i| (neighbours[i].velocity, neighbours[i].with));
+
+        let within: Vec<_> = neighbours
+            .into_iter()
+            .filter(|n| (n.position - BOID).magnitude2() < species.sep_radius_2)
+            .collect();
+        assert_eq!(lanes.len(), within.len());
+        assert_same(lanes.sum(BOID, &species), scalar_sum(&within, &species));
+    }
+}
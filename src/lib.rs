@@ -12,9 +12,24 @@ extern crate serde;
 
 pub mod boids;
 pub mod config;
+pub mod headless;
+pub mod kernel;
+pub mod obstacle;
+pub mod predator;
+pub mod snapshot;
+pub mod species;
 pub mod system;
 
 mod event;
+mod executor;
 mod fps;
 mod glx;
+mod grid;
+mod lanes;
+mod record;
 mod render;
+mod spatial_hash;
+mod timestep;
+mod tune;
+mod validate;
+mod watch;
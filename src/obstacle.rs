@@ -0,0 +1,175 @@
+use std::f32::consts::PI;
+
+use cgmath::{InnerSpace, Point2, Vector2};
+
+type Position = Point2<f32>;
+
+// Number of line segments used to draw a circle
+const CIRCLE_SEGMENTS: usize = 32;
+
+/// Static geometry that boids steer around, in world units.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Obstacle {
+    Circle { centre: (f32, f32), radius: f32 },
+    Rect { min: (f32, f32), max: (f32, f32) },
+    Wall { start: (f32, f32), end: (f32, f32) },
+}
+
+impl Obstacle {
+    /// The closest point on the edge of the obstacle, and whether `p` is inside of it.
+    pub fn closest_point(&self, p: Position) -> (Position, bool) {
+        match *self {
+            Obstacle::Circle { centre, radius } => {
+                let centre = Position::from(centre);
+                let from_centre = p - centre;
+                let dist = from_centre.magnitude();
+                if dist > 0. {
+                    (centre + from_centre * (radius / dist), dist < radius)
+                } else {
+                    (centre + Vector2::new(radius, 0.), true)
+                }
+            }
+            Obstacle::Rect { min, max } => {
+                let inside = p.x > min.0 && p.x < max.0 && p.y > min.1 && p.y < max.1;
+                if inside {
+                    // Push out through whichever edge is nearest
+                    let edges = [
+                        (p.x - min.0, Position::new(min.0, p.y)),
+                        (max.0 - p.x, Position::new(max.0, p.y)),
+                        (p.y - min.1, Position::new(p.x, min.1)),
+                        (max.1 - p.y, Position::new(p.x, max.1)),
+                    ];
+                    let (_, nearest) = edges
+                        .iter()
+                        .fold(edges[0], |a, &b| if b.0 < a.0 { b } else { a });
+                    (nearest, true)
+                } else {
+                    let x = p.x.max(min.0).min(max.0);
+                    let y = p.y.max(min.1).min(max.1);
+                    (Position::new(x, y), false)
+                }
+            }
+            Obstacle::Wall { start, end } => {
+                let start = Position::from(start);
+                let along = Position::from(end) - start;
+                let len_sq = along.magnitude2();
+                let t = if len_sq > 0. {
+                    ((p - start).dot(along) / len_sq).clamp(0., 1.)
+                } else {
+                    0.
+                };
+                (start + along * t, false)
+            }
+        }
+    }
+
+    /// Stretch the obstacle along with a world that has been stretched by `sx` and `sy`.
+    pub(crate) fn scale(&mut self, sx: f32, sy: f32) {
+        let scale = |(x, y): (f32, f32)| (x * sx, y * sy);
+        match *self {
+            Obstacle::Circle {
+                ref mut centre,
+                ref mut radius,
+            } => {
+                *centre = scale(*centre);
+                // Circles stay round, so take the average stretch
+                *radius *= (sx * sy).sqrt();
+            }
+            Obstacle::Rect {
+                ref mut min,
+                ref mut max,
+            } => {
+                *min = scale(*min);
+                *max = scale(*max);
+            }
+            Obstacle::Wall {
+                ref mut start,
+                ref mut end,
+            } => {
+                *start = scale(*start);
+                *end = scale(*end);
+            }
+        }
+    }
+
+    /// The outline of the obstacle as pairs of points, one pair per line segment.
+    pub fn outline(&self) -> Vec<Position> {
+        let points = match *self {
+            Obstacle::Circle { centre, radius } => (0..CIRCLE_SEGMENTS)
+                .map(|i| {
+                    let a = 2. * PI * i as f32 / CIRCLE_SEGMENTS as f32;
+                    Position::new(centre.0 + radius * a.cos(), centre.1 + radius * a.sin())
+                })
+                .collect(),
+            Obstacle::Rect { min, max } => vec![
+                Position::new(min.0, min.1),
+                Position::new(max.0, min.1),
+                Position::new(max.0, max.1),
+                Position::new(min.0, max.1),
+            ],
+            Obstacle::Wall { start, end } => {
+                return vec![Position::from(start), Position::from(end)];
+            }
+        };
+        // Close the loop of points into segments
+        let next = points.iter().cycle().skip(1);
+        points
+            .iter()
+            .zip(next)
+            .flat_map(|(&a, &b)| vec![a, b])
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn closest(obstacle: &Obstacle, x: f32, y: f32) -> ((f32, f32), bool) {
+        let (point, inside) = obstacle.closest_point(Position::new(x, y));
+        ((point.x, point.y), inside)
+    }
+
+    #[test]
+    fn circles_push_out_along_the_radius() {
+        let circle = Obstacle::Circle {
+            centre: (0., 0.),
+            radius: 5.,
+        };
+        assert_eq!(closest(&circle, 10., 0.), ((5., 0.), false));
+        assert_eq!(closest(&circle, 0., -2.), ((0., -5.), true));
+        assert_eq!(closest(&circle, 0., 5.), ((0., 5.), false));
+        // The centre has no direction out of its own, so one is picked
+        assert_eq!(closest(&circle, 0., 0.), ((5., 0.), true));
+    }
+
+    #[test]
+    fn rects_push_out_through_the_nearest_edge() {
+        let rect = Obstacle::Rect {
+            min: (0., 0.),
+            max: (10., 4.),
+        };
+        assert_eq!(closest(&rect, 15., 2.), ((10., 2.), false));
+        assert_eq!(closest(&rect, 12., -3.), ((10., 0.), false));
+        assert_eq!(closest(&rect, 2., 1.), ((2., 0.), true));
+        assert_eq!(closest(&rect, 9., 2.5), ((10., 2.5), true));
+        assert_eq!(closest(&rect, 10., 2.), ((10., 2.), false));
+    }
+
+    #[test]
+    fn walls_are_never_inside() {
+        let wall = Obstacle::Wall {
+            start: (0., 0.),
+            end: (10., 0.),
+        };
+        assert_eq!(closest(&wall, 4., 3.), ((4., 0.), false));
+        assert_eq!(closest(&wall, 13., 4.), ((10., 0.), false));
+        assert_eq!(closest(&wall, -1., -1.), ((0., 0.), false));
+        assert_eq!(closest(&wall, 4., 0.), ((4., 0.), false));
+        let point = Obstacle::Wall {
+            start: (3., 3.),
+            end: (3., 3.),
+        };
+        assert_eq!(closest(&point, 5., 5.), ((3., 3.), false));
+    }
+}
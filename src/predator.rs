@@ -0,0 +1,150 @@
+use cgmath::{Basis2, InnerSpace, Point2, Rad, Rotation, Rotation2, Vector2};
+
+type Position = Point2<f32>;
+type Velocity = Vector2<f32>;
+
+/// How a predator picks which part of the flock to go after.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChaseStrategy {
+    /// Head for the closest boid.
+    Nearest,
+    /// Head for the most crowded part of the world.
+    Densest,
+}
+
+/// Speeds are in world units per second, turn rate is in radians per second.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PredatorConfig {
+    pub count: u32,
+    pub max_speed: f32,
+    pub turn_rate: f32,
+    pub strategy: ChaseStrategy,
+    pub flee_radius: f32,
+    pub flee_weight: f32,
+}
+
+impl Default for PredatorConfig {
+    fn default() -> PredatorConfig {
+        PredatorConfig {
+            count: 0,
+            max_speed: 180.,
+            turn_rate: 3.,
+            strategy: ChaseStrategy::Nearest,
+            flee_radius: 60.,
+            flee_weight: 4.,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Predator {
+    pub(crate) position: Position,
+    pub(crate) velocity: Velocity,
+}
+
+impl Predator {
+    pub fn position(&self) -> Point2<f32> {
+        self.position
+    }
+
+    pub fn velocity(&self) -> Vector2<f32> {
+        self.velocity
+    }
+
+    /// Turn towards the target as fast as the turn rate allows, then move at full speed.
+    pub(crate) fn hunt(&mut self, target: Option<Position>, conf: &PredatorConfig, dt: f32) {
+        let heading = if self.velocity.magnitude2() > 0. {
+            self.velocity.normalize()
+        } else {
+            Vector2::new(1., 0.)
+        };
+        let heading = match target.map(|t| t - self.position) {
+            Some(to_target) if to_target.magnitude2() > 0. => {
+                let angle = heading.angle(to_target).0;
+                let max_turn = conf.turn_rate * dt;
+                let turn = angle.max(-max_turn).min(max_turn);
+                Basis2::from_angle(Rad(turn)).rotate_vector(heading)
+            }
+            _ => heading,
+        };
+        self.velocity = heading * conf.max_speed;
+        self.position += self.velocity * dt;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::*;
+
+    const DT: f32 = 1. / 60.;
+
+    fn predator() -> Predator {
+        Predator {
+            position: Position::new(0., 0.),
+            velocity: Velocity::new(180., 0.),
+        }
+    }
+
+    #[test]
+    fn hunting_turns_towards_the_target_no_faster_than_the_turn_rate() {
+        let conf = PredatorConfig::default();
+        let max_turn = conf.turn_rate * DT;
+        for i in 1..36 {
+            // Targets all the way round, either side of straight ahead
+            let bearing = (i as f32 * 10. - 180.).to_radians();
+            let mut p = predator();
+            let target = Position::new(100. * bearing.cos(), 100. * bearing.sin());
+            p.hunt(Some(target), &conf, DT);
+            let turn = Vector2::new(1., 0.).angle(p.velocity).0;
+            assert!(turn.abs() <= max_turn + 1e-5, "{} for {}", turn, bearing);
+            assert_eq!(
+                turn.signum(),
+                bearing.signum(),
+                "turned away from {}",
+                bearing
+            );
+            assert!((p.velocity.magnitude() - conf.max_speed).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn hunting_heads_straight_for_a_target_within_one_turn() {
+        let conf = PredatorConfig::default();
+        let bearing = conf.turn_rate * DT / 2.;
+        let mut p = predator();
+        p.hunt(Some(Position::new(bearing.cos(), bearing.sin())), &conf, DT);
+        let turn = Vector2::new(1., 0.).angle(p.velocity).0;
+        assert!((turn - bearing).abs() < 1e-5);
+    }
+
+    #[test]
+    fn hunting_closes_on_a_target_behind_the_predator() {
+        let conf = PredatorConfig::default();
+        let target = Position::new(-300., 40.);
+        let mut p = predator();
+        let start = (target - p.position).magnitude();
+        let mut closest = start;
+        for _ in 0..(4. * PI / (conf.turn_rate * DT)) as usize {
+            p.hunt(Some(target), &conf, DT);
+            closest = closest.min((target - p.position).magnitude());
+        }
+        assert!(
+            closest < conf.max_speed * DT,
+            "got within {} of {}",
+            closest,
+            start
+        );
+    }
+
+    #[test]
+    fn without_a_target_the_predator_carries_on() {
+        let conf = PredatorConfig::default();
+        let mut p = predator();
+        p.hunt(None, &conf, DT);
+        assert_eq!(p.velocity, Velocity::new(conf.max_speed, 0.));
+        assert_eq!(p.position, Position::new(conf.max_speed * DT, 0.));
+    }
+}
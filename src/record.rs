@@ -0,0 +1,298 @@
+// A recording is a short header followed by a stream of bincode encoded records. The
+// first record is always a snapshot of the flock, followed by the control events fed to
+// the simulation, each tagged with the step it arrived on.
+
+use std::error::{self, Error};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+
+use crate::event::BoidControlEvent;
+use crate::snapshot::SnapshotError;
+use crate::system::FlockingSystem;
+
+const MAGIC: &[u8; 4] = b"FLKR";
+const VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum RecordingError {
+    Io(io::Error),
+    Encoding(bincode::Error),
+    Snapshot(SnapshotError),
+    NotARecording,
+    Version(u32),
+}
+
+impl From<io::Error> for RecordingError {
+    fn from(err: io::Error) -> RecordingError {
+        RecordingError::Io(err)
+    }
+}
+
+impl From<bincode::Error> for RecordingError {
+    fn from(err: bincode::Error) -> RecordingError {
+        RecordingError::Encoding(err)
+    }
+}
+
+impl From<SnapshotError> for RecordingError {
+    fn from(err: SnapshotError) -> RecordingError {
+        RecordingError::Snapshot(err)
+    }
+}
+
+impl fmt::Display for RecordingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RecordingError::Io(ref err) => write!(f, "Could not access recording: {}", err),
+            RecordingError::Encoding(ref err) => write!(f, "Could not encode recording: {}", err),
+            RecordingError::Snapshot(ref err) => write!(f, "Bad snapshot in recording: {}", err),
+            RecordingError::NotARecording => write!(f, "File is not a flock recording"),
+            RecordingError::Version(v) => write!(
+                f,
+                "Recording is version {}, only version {} is supported",
+                v, VERSION
+            ),
+        }
+    }
+}
+
+impl error::Error for RecordingError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            RecordingError::Io(ref err) => Some(err),
+            RecordingError::Encoding(ref err) => Some(err),
+            RecordingError::Snapshot(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum Record {
+    Event { step: u64, event: BoidControlEvent },
+    Restore { step: u64, snapshot: Vec<u8> },
+}
+
+impl Record {
+    fn step(&self) -> u64 {
+        match *self {
+            Record::Event { step, .. } | Record::Restore { step, .. } => step,
+        }
+    }
+}
+
+pub struct Recorder {
+    writer: BufWriter<File>,
+}
+
+impl Recorder {
+    /// Start a recording with a snapshot of the flock as it is now.
+    pub fn create(path: &str, simulation: &FlockingSystem) -> Result<Recorder, RecordingError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        let mut recorder = Recorder { writer };
+        recorder.record_restore(simulation.steps(), simulation)?;
+        Ok(recorder)
+    }
+
+    pub fn record_event(
+        &mut self,
+        step: u64,
+        event: &BoidControlEvent,
+    ) -> Result<(), RecordingError> {
+        let event = event.clone();
+        bincode::serialize_into(&mut self.writer, &Record::Event { step, event })?;
+        Ok(())
+    }
+
+    /// Record the flock having been replaced wholesale on `step`, e.g. by loading a snapshot.
+    ///
+    /// The step is the one the flock was on before, as a loaded snapshot brings its own.
+    pub fn record_restore(
+        &mut self,
+        step: u64,
+        simulation: &FlockingSystem,
+    ) -> Result<(), RecordingError> {
+        let mut snapshot = Vec::new();
+        simulation.save_snapshot(&mut snapshot)?;
+        bincode::serialize_into(&mut self.writer, &Record::Restore { step, snapshot })?;
+        Ok(())
+    }
+
+    /// Write out anything still buffered, ending the recording.
+    pub fn finish(mut self) -> Result<(), RecordingError> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+pub struct Replay {
+    records: Vec<Record>,
+    next: usize,
+}
+
+impl Replay {
+    pub fn open(path: &str) -> Result<Replay, RecordingError> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(RecordingError::NotARecording);
+        }
+        let mut version = [0; 4];
+        reader.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != VERSION {
+            return Err(RecordingError::Version(version));
+        }
+
+        let mut records = Vec::new();
+        loop {
+            match bincode::deserialize_from(&mut reader) {
+                Ok(record) => records.push(record),
+                Err(err) => match *err {
+                    bincode::ErrorKind::Io(ref io_err)
+                        if io_err.kind() == ErrorKind::UnexpectedEof =>
+                    {
+                        break
+                    }
+                    _ => return Err(err.into()),
+                },
+            }
+        }
+        match records.first() {
+            Some(Record::Restore { .. }) => Ok(Replay { records, next: 0 }),
+            _ => Err(RecordingError::NotARecording),
+        }
+    }
+
+    /// Feed the simulation everything recorded up to the step it is on.
+    pub fn apply_due<F>(
+        &mut self,
+        simulation: &mut FlockingSystem,
+        mut apply_event: F,
+    ) -> Result<(), RecordingError>
+    where
+        F: FnMut(&mut FlockingSystem, BoidControlEvent),
+    {
+        // The first record is a restore, which will always be due
+        while self.next < self.records.len()
+            && (self.next == 0 || self.records[self.next].step() <= simulation.steps())
+        {
+            match self.records[self.next] {
+                Record::Event { ref event, .. } => apply_event(simulation, event.clone()),
+                Record::Restore { ref snapshot, .. } => simulation.load_snapshot(&snapshot[..])?,
+            }
+            self.next += 1;
+        }
+        Ok(())
+    }
+
+    pub fn finished(&self) -> bool {
+        self.next >= self.records.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use winit::event::VirtualKeyCode;
+
+    use super::*;
+    use crate::boids::{apply_event, build_flocking_config, SimulationConfig};
+
+    const DT: f32 = 1. / 60.;
+
+    fn flock(seed: u64) -> FlockingSystem {
+        let config = SimulationConfig {
+            boid_count: 100,
+            seed: Some(seed),
+            threads: 1,
+            ..SimulationConfig::default()
+        };
+        let mut flock = FlockingSystem::new(build_flocking_config(&config, 200., 200.));
+        flock.randomise();
+        flock
+    }
+
+    // A file of its own for each test, as tests run at the same time
+    fn temp_path(name: &str) -> String {
+        let path = env::temp_dir().join(format!("{}-{}.flkr", name, process::id()));
+        path.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn a_replay_steps_exactly_as_the_recorded_run_did() {
+        let path = temp_path("replay");
+        let events = [
+            (5, BoidControlEvent::MouseMove(50., 60.)),
+            (5, BoidControlEvent::MousePress),
+            (12, BoidControlEvent::Key(VirtualKeyCode::R)),
+            (20, BoidControlEvent::MouseRelease),
+            (33, BoidControlEvent::Resize(300, 150)),
+            (45, BoidControlEvent::Key(VirtualKeyCode::C)),
+        ];
+        let mut live = flock(1);
+        let mut recorder = Recorder::create(&path, &live).unwrap();
+        let mut snapshot = Vec::new();
+        let mut steps = Vec::new();
+        for i in 0..60 {
+            for (_, event) in events.iter().filter(|&&(at, _)| at == i) {
+                recorder.record_event(live.steps(), event).unwrap();
+                apply_event(&mut live, event.clone());
+            }
+            // Loaded part way through, taking the flock back to how it was
+            if i == 15 {
+                live.save_snapshot(&mut snapshot).unwrap();
+            }
+            if i == 40 {
+                let step = live.steps();
+                live.load_snapshot(&snapshot[..]).unwrap();
+                recorder.record_restore(step, &live).unwrap();
+            }
+            live.update(DT);
+            steps.push(live.grid_positions().to_vec());
+        }
+        recorder.finish().unwrap();
+
+        let mut replayed = flock(2);
+        let replay = Replay::open(&path);
+        fs::remove_file(&path).unwrap();
+        let mut replay = replay.unwrap();
+        for (i, positions) in steps.iter().enumerate() {
+            replay.apply_due(&mut replayed, apply_event).unwrap();
+            replayed.update(DT);
+            assert!(replayed.grid_positions() == &positions[..], "step {}", i);
+        }
+        assert!(replay.finished());
+    }
+
+    #[test]
+    fn files_without_the_magic_are_not_recordings() {
+        let path = temp_path("magic");
+        fs::write(&path, b"FLOK\x01\x00\x00\x00").unwrap();
+        let replay = Replay::open(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(replay, Err(RecordingError::NotARecording)));
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let path = temp_path("version");
+        let mut contents = MAGIC.to_vec();
+        contents.extend((VERSION + 1).to_le_bytes());
+        fs::write(&path, contents).unwrap();
+        let replay = Replay::open(&path);
+        fs::remove_file(&path).unwrap();
+        match replay {
+            Err(RecordingError::Version(v)) => assert_eq!(v, VERSION + 1),
+            Err(err) => panic!("expected a version error, got {}", err),
+            Ok(_) => panic!("expected a version error, got a replay"),
+        }
+    }
+}
@@ -1,20 +1,23 @@
 use std::{mem, ptr};
 
-use crate::system::Boid;
-use cgmath::{Matrix, Matrix3, Point2};
+use crate::system::FlockingSystem;
+use cgmath::{Matrix, Matrix3, Point2, Vector2};
 use gl::{self, types::*};
 
-use crate::glx::{self, Buffer, ShaderProgram, VertexArray};
+use crate::glx::{self, Buffer, ShaderError, ShaderProgram, VertexArray};
 
 // Shader sources
-static VS_SRC: &'static str = "
+static VS_SRC: &str = "
     #version 330 core
     layout (location = 0) in vec2 position;
     layout (location = 1) in vec2 velocity;
+    layout (location = 2) in uint species;
 
     uniform mat3 transform;
     uniform float pointSize;
     uniform float maxSpeedSquared;
+    // An alpha of zero means the species is coloured by heading
+    uniform vec4 speciesColours[8];
 
     out vec4 pointColor;
 
@@ -33,12 +36,23 @@ static VS_SRC: &'static str = "
 
     float a = atan(velocity.y, velocity.x);
     void main() {
-        pointColor = vec4(rgb_from_hsb(vec3(a/two_pi, 1 - (mag_2 / maxSpeedSquared), 1.0)), 1.0);
+        if (isinf(position.x)) {
+            // Sentinel padding the boid grid, place it outside the clip volume
+            gl_Position = vec4(2.0, 2.0, 2.0, 1.0);
+            return;
+        }
+        float saturation = 1 - (mag_2 / maxSpeedSquared);
+        vec4 tint = speciesColours[min(species, 7u)];
+        if (tint.a > 0.0) {
+            pointColor = vec4(mix(vec3(1.0), tint.rgb, saturation), 1.0);
+        } else {
+            pointColor = vec4(rgb_from_hsb(vec3(a/two_pi, saturation, 1.0)), 1.0);
+        }
         gl_PointSize = pointSize;
         gl_Position = vec4(transform * vec3(position, 1.0), 1.0);
     }";
 
-static FS_SRC: &'static str = "
+static FS_SRC: &str = "
     #version 330 core
     out vec4 frag_colour;
 
@@ -48,114 +62,324 @@ static FS_SRC: &'static str = "
         frag_colour = pointColor;
     }";
 
-//TODO: Handle resizing of screen
+// Shapes are lines or points drawn in a single colour
+static SHAPE_VS_SRC: &str = "
+    #version 330 core
+    layout (location = 0) in vec2 position;
+
+    uniform mat3 transform;
+    uniform float pointSize;
+
+    void main() {
+        gl_PointSize = pointSize;
+        gl_Position = vec4(transform * vec3(position, 1.0), 1.0);
+    }";
+
+static SHAPE_FS_SRC: &str = "
+    #version 330 core
+    out vec4 frag_colour;
+
+    uniform vec3 colour;
+
+    void main() {
+        frag_colour = vec4(colour, 1.0);
+    }";
+
+const OBSTACLE_COLOUR: (f32, f32, f32) = (0.8, 0.8, 0.8);
+const PREDATOR_COLOUR: (f32, f32, f32) = (1.0, 0.2, 0.2);
+// Must match the length of the speciesColours uniform, later species share the last colour
+const MAX_SPECIES: usize = 8;
+
 //TODO: How to run at different resolutions
 
 pub struct RendererConfig {
     pub width: f32,
     pub height: f32,
     pub boid_size: f32,
+    pub predator_size: f32,
     pub max_speed: f32,
+    pub species_colours: Vec<Option<(f32, f32, f32)>>,
 }
 
 pub struct Renderer {
+    world_size: (f32, f32),
     transform: Matrix3<f32>,
     boid_size: f32,
+    predator_size: f32,
     max_speed: f32,
+    species_colours: [[f32; 4]; MAX_SPECIES],
     program: ShaderProgram,
+    uniforms: BoidUniforms,
     vao: VertexArray,
-    vbo: Buffer,
+    // One for each field of the boid grid, uploaded straight from the simulation
+    position_vbo: Buffer,
+    velocity_vbo: Buffer,
+    species_vbo: Buffer,
+    shape_program: ShaderProgram,
+    shape_uniforms: ShapeUniforms,
+    shape_vao: VertexArray,
+    shape_vbo: Buffer,
+    shape_vertices: Vec<Point2<f32>>,
+}
+
+// Uniform locations are looked up once, when the programs are built
+struct BoidUniforms {
+    transform: GLint,
+    point_size: GLint,
+    max_speed_squared: GLint,
+    species_colours: GLint,
+}
+
+struct ShapeUniforms {
+    transform: GLint,
+    point_size: GLint,
+    colour: GLint,
+}
+
+impl BoidUniforms {
+    fn find(program: &ShaderProgram) -> Result<BoidUniforms, ShaderError> {
+        Ok(BoidUniforms {
+            transform: program.get_uniform_location("transform")?,
+            point_size: program.get_uniform_location("pointSize")?,
+            max_speed_squared: program.get_uniform_location("maxSpeedSquared")?,
+            species_colours: program.get_uniform_location("speciesColours")?,
+        })
+    }
+}
+
+impl ShapeUniforms {
+    fn find(program: &ShaderProgram) -> Result<ShapeUniforms, ShaderError> {
+        Ok(ShapeUniforms {
+            transform: program.get_uniform_location("transform")?,
+            point_size: program.get_uniform_location("pointSize")?,
+            colour: program.get_uniform_location("colour")?,
+        })
+    }
 }
 
 impl Renderer {
-    pub fn new(config: RendererConfig) -> Renderer {
-        let program = ShaderProgram::new(VS_SRC, FS_SRC).expect("Problem creating shader program");
+    pub fn new(config: RendererConfig) -> Result<Renderer, ShaderError> {
+        let program = ShaderProgram::new(VS_SRC, FS_SRC)?;
+        let shape_program = ShaderProgram::new(SHAPE_VS_SRC, SHAPE_FS_SRC)?;
+        let uniforms = BoidUniforms::find(&program)?;
+        let shape_uniforms = ShapeUniforms::find(&shape_program)?;
 
-        Renderer {
+        Ok(Renderer {
+            world_size: (config.width, config.height),
             transform: glx::vtx_transform_2d(config.width, config.height),
             boid_size: config.boid_size,
+            predator_size: config.predator_size,
             max_speed: config.max_speed,
+            species_colours: pack_species_colours(&config.species_colours),
             program,
+            uniforms,
             vao: VertexArray::new(),
-            vbo: Buffer::new(),
-        }
+            position_vbo: Buffer::new(),
+            velocity_vbo: Buffer::new(),
+            species_vbo: Buffer::new(),
+            shape_program,
+            shape_uniforms,
+            shape_vao: VertexArray::new(),
+            shape_vbo: Buffer::new(),
+            shape_vertices: Vec::new(),
+        })
     }
 
-    pub fn init_pipeline(&self) {
+    pub fn init_pipeline(&self) -> Result<(), ShaderError> {
         unsafe {
             self.vao.bind();
-            self.vbo.bind(gl::ARRAY_BUFFER);
-            self.program.activate();
-
-            // Set the tranform uniform
-            let trans_loc = self
-                .program
-                .get_uniform_location("transform")
-                .expect("Could not find uniform");
-            gl::UniformMatrix3fv(trans_loc, 1, gl::FALSE, self.transform.as_ptr());
-
-            // Set the point size
-            let size_loc = self
-                .program
-                .get_uniform_location("pointSize")
-                .expect("Could not find uniform");
-            gl::Uniform1f(size_loc, self.boid_size as GLfloat);
+            self.upload_boid_uniforms();
 
-            // Set max speed
-            let max_speed_loc = self
-                .program
-                .get_uniform_location("maxSpeedSquared")
-                .expect("Could not find uniform");
-            gl::Uniform1f(max_speed_loc, self.max_speed.powi(2) as GLfloat);
-
-            // Specify the layout of the vertex data
-            let pos_loc = self
-                .program
-                .get_atrib_location("position")
-                .expect("could not find position");
+            // Specify the layout of the vertex data, each attribute from its own buffer
+            self.position_vbo.bind(gl::ARRAY_BUFFER);
+            let pos_loc = self.program.get_atrib_location("position")?;
             gl::EnableVertexAttribArray(pos_loc);
             gl::VertexAttribPointer(
                 pos_loc,
                 2,
                 gl::FLOAT,
                 gl::FALSE,
-                mem::size_of::<Boid>() as GLsizei,
+                mem::size_of::<Point2<f32>>() as GLsizei,
                 ptr::null(),
             );
 
-            let vel_loc = self
-                .program
-                .get_atrib_location("velocity")
-                .expect("could not find velocity");
+            self.velocity_vbo.bind(gl::ARRAY_BUFFER);
+            let vel_loc = self.program.get_atrib_location("velocity")?;
             gl::EnableVertexAttribArray(vel_loc);
             gl::VertexAttribPointer(
                 vel_loc,
                 2,
                 gl::FLOAT,
                 gl::FALSE,
-                mem::size_of::<Boid>() as GLsizei,
-                mem::size_of::<Point2<f32>>() as *const GLvoid,
+                mem::size_of::<Vector2<f32>>() as GLsizei,
+                ptr::null(),
+            );
+
+            self.species_vbo.bind(gl::ARRAY_BUFFER);
+            let species_loc = self.program.get_atrib_location("species")?;
+            gl::EnableVertexAttribArray(species_loc);
+            gl::VertexAttribIPointer(
+                species_loc,
+                1,
+                gl::UNSIGNED_INT,
+                mem::size_of::<u32>() as GLsizei,
+                ptr::null(),
             );
 
             // Allow shader to specify point size
             gl::Enable(gl::PROGRAM_POINT_SIZE);
+
+            // Shapes are drawn with their own program and vertex layout
+            self.shape_vao.bind();
+            self.shape_vbo.bind(gl::ARRAY_BUFFER);
+            self.shape_program.activate();
+
+            let pos_loc = self.shape_program.get_atrib_location("position")?;
+            gl::EnableVertexAttribArray(pos_loc);
+            gl::VertexAttribPointer(
+                pos_loc,
+                2,
+                gl::FLOAT,
+                gl::FALSE,
+                mem::size_of::<Point2<f32>>() as GLsizei,
+                ptr::null(),
+            );
+        }
+        self.upload_transform();
+        Ok(())
+    }
+
+    /// Pick up new sizes, speeds and colours, keeping the current world size.
+    pub fn reconfigure(&mut self, config: RendererConfig) {
+        self.boid_size = config.boid_size;
+        self.predator_size = config.predator_size;
+        self.max_speed = config.max_speed;
+        self.species_colours = pack_species_colours(&config.species_colours);
+        self.upload_boid_uniforms();
+    }
+
+    /// Draw into the whole of a window that is now `width` by `height` pixels.
+    pub fn resize_viewport(&self, width: u32, height: u32) {
+        unsafe {
+            gl::Viewport(0, 0, width as GLsizei, height as GLsizei);
         }
     }
 
-    pub fn render(&self, boids: &[Boid]) {
+    pub fn render(&mut self, simulation: &FlockingSystem) {
+        // The world can change size underneath us, from a resize, snapshot or replay
+        let world_size = (simulation.width(), simulation.height());
+        if world_size != self.world_size {
+            self.world_size = world_size;
+            self.transform = glx::vtx_transform_2d(world_size.0, world_size.1);
+            self.upload_transform();
+        }
         glx::clear_screen(0.1, 0.1, 0.1);
+        self.render_boids(simulation);
+
+        self.shape_vertices.clear();
+        for obstacle in simulation.obstacles() {
+            self.shape_vertices.extend(obstacle.outline());
+        }
+        self.render_shapes(gl::LINES, OBSTACLE_COLOUR, 1.);
+
+        self.shape_vertices.clear();
+        let predators = simulation.predators().iter().map(|p| p.position());
+        self.shape_vertices.extend(predators);
+        self.render_shapes(gl::POINTS, PREDATOR_COLOUR, self.predator_size);
+    }
+
+    fn upload_boid_uniforms(&self) {
         unsafe {
-            // This _should_ implement buffer orphaning
-            gl::BufferData(gl::ARRAY_BUFFER, 0, ptr::null(), gl::STREAM_DRAW);
+            self.program.activate();
+
+            // Set the point size
+            gl::Uniform1f(self.uniforms.point_size, self.boid_size as GLfloat);
+
+            // Set max speed
+            let max_speed_squared = self.max_speed.powi(2) as GLfloat;
+            gl::Uniform1f(self.uniforms.max_speed_squared, max_speed_squared);
+
+            // Set the species colours
+            gl::Uniform4fv(
+                self.uniforms.species_colours,
+                MAX_SPECIES as GLsizei,
+                self.species_colours.as_ptr() as *const GLfloat,
+            );
+        }
+    }
+
+    // Both programs map world units onto the window with the same transform
+    fn upload_transform(&self) {
+        let programs = [
+            (&self.program, self.uniforms.transform),
+            (&self.shape_program, self.shape_uniforms.transform),
+        ];
+        for (program, trans_loc) in programs {
+            unsafe {
+                program.activate();
+                gl::UniformMatrix3fv(trans_loc, 1, gl::FALSE, self.transform.as_ptr());
+            }
+        }
+    }
+
+    fn render_boids(&self, simulation: &FlockingSystem) {
+        let positions = simulation.grid_positions();
+        unsafe {
+            self.vao.bind();
+            self.program.activate();
+            stream(&self.position_vbo, positions);
+            stream(&self.velocity_vbo, simulation.grid_velocities());
+            stream(&self.species_vbo, simulation.grid_species());
+            gl::DrawArrays(gl::POINTS, 0, positions.len() as i32);
+        }
+    }
 
+    fn render_shapes(&self, mode: GLenum, colour: (f32, f32, f32), point_size: f32) {
+        if self.shape_vertices.is_empty() {
+            return;
+        }
+        unsafe {
+            self.shape_vao.bind();
+            self.shape_vbo.bind(gl::ARRAY_BUFFER);
+            self.shape_program.activate();
+
+            let (r, g, b) = colour;
+            gl::Uniform3f(self.shape_uniforms.colour, r, g, b);
+            gl::Uniform1f(self.shape_uniforms.point_size, point_size as GLfloat);
+
+            gl::BufferData(gl::ARRAY_BUFFER, 0, ptr::null(), gl::STREAM_DRAW);
             gl::BufferData(
                 gl::ARRAY_BUFFER,
-                (boids.len() * mem::size_of::<Boid>()) as GLsizeiptr,
-                boids.as_ptr() as *const _,
+                (self.shape_vertices.len() * mem::size_of::<Point2<f32>>()) as GLsizeiptr,
+                self.shape_vertices.as_ptr() as *const _,
                 gl::STREAM_DRAW,
             );
 
-            gl::DrawArrays(gl::POINTS, 0, boids.len() as i32);
+            gl::DrawArrays(mode, 0, self.shape_vertices.len() as i32);
+        }
+    }
+}
+
+// Replace the contents of a buffer with the slice, as it is laid out in memory
+unsafe fn stream<T>(buffer: &Buffer, data: &[T]) {
+    buffer.bind(gl::ARRAY_BUFFER);
+    // This _should_ implement buffer orphaning
+    gl::BufferData(gl::ARRAY_BUFFER, 0, ptr::null(), gl::STREAM_DRAW);
+    gl::BufferData(
+        gl::ARRAY_BUFFER,
+        mem::size_of_val(data) as GLsizeiptr,
+        data.as_ptr() as *const _,
+        gl::STREAM_DRAW,
+    );
+}
+
+// Species without a colour keep an alpha of 0, which the shader takes as colour by heading
+fn pack_species_colours(colours: &[Option<(f32, f32, f32)>]) -> [[f32; 4]; MAX_SPECIES] {
+    let mut packed = [[0.; 4]; MAX_SPECIES];
+    for (slot, colour) in packed.iter_mut().zip(colours) {
+        if let Some((r, g, b)) = *colour {
+            *slot = [r, g, b, 1.];
         }
     }
+    packed
 }
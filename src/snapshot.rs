@@ -0,0 +1,125 @@
+// Snapshots are a short header identifying the format and its version, followed by
+// the bincode encoded state of the flock.
+
+use std::error::{self, Error};
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+const MAGIC: &[u8; 4] = b"FLOK";
+const VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    Encoding(bincode::Error),
+    NotASnapshot,
+    Version(u32),
+    Invalid(String),
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> SnapshotError {
+        SnapshotError::Io(err)
+    }
+}
+
+impl From<bincode::Error> for SnapshotError {
+    fn from(err: bincode::Error) -> SnapshotError {
+        SnapshotError::Encoding(err)
+    }
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SnapshotError::Io(ref err) => write!(f, "Could not access snapshot: {}", err),
+            SnapshotError::Encoding(ref err) => write!(f, "Could not encode snapshot: {}", err),
+            SnapshotError::NotASnapshot => write!(f, "File is not a flock snapshot"),
+            SnapshotError::Version(v) => write!(
+                f,
+                "Snapshot is version {}, only version {} is supported",
+                v, VERSION
+            ),
+            SnapshotError::Invalid(ref err) => write!(f, "Snapshot is invalid: {}", err),
+        }
+    }
+}
+
+impl error::Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            SnapshotError::Io(ref err) => Some(err),
+            SnapshotError::Encoding(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) fn write<W, T>(mut writer: W, state: &T) -> Result<(), SnapshotError>
+where
+    W: Write,
+    T: Serialize,
+{
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    bincode::serialize_into(&mut writer, state)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub(crate) fn read<R, T>(mut reader: R) -> Result<T, SnapshotError>
+where
+    R: Read,
+    T: DeserializeOwned,
+{
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(SnapshotError::NotASnapshot);
+    }
+    let mut version = [0; 4];
+    reader.read_exact(&mut version)?;
+    let version = u32::from_le_bytes(version);
+    if version != VERSION {
+        return Err(SnapshotError::Version(version));
+    }
+    Ok(bincode::deserialize_from(reader)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_comes_back_as_it_was_written() {
+        let state = (String::from("flock"), vec![1.5f32, -2., 3.25], 42u64);
+        let mut bytes = Vec::new();
+        write(&mut bytes, &state).unwrap();
+        let read: (String, Vec<f32>, u64) = read(&bytes[..]).unwrap();
+        assert_eq!(read, state);
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut bytes = Vec::new();
+        write(&mut bytes, &42u64).unwrap();
+        bytes[4..8].copy_from_slice(&(VERSION - 1).to_le_bytes());
+        match read::<_, u64>(&bytes[..]) {
+            Err(SnapshotError::Version(v)) => assert_eq!(v, VERSION - 1),
+            other => panic!("expected a version error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn files_without_the_magic_are_not_snapshots() {
+        let mut bytes = Vec::new();
+        write(&mut bytes, &42u64).unwrap();
+        bytes[0] = b'X';
+        assert!(matches!(
+            read::<_, u64>(&bytes[..]),
+            Err(SnapshotError::NotASnapshot)
+        ));
+    }
+}
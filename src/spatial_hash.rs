@@ -0,0 +1,96 @@
+// A uniform grid of buckets, for finding every point within a radius rather than an
+// approximation of them. Rebuilt from scratch whenever the points move.
+
+use cgmath::{InnerSpace, Point2};
+
+// Past this many cells a side, cells grow beyond the query radius instead
+const MAX_CELLS_PER_SIDE: usize = 1024;
+
+pub(crate) struct SpatialHash {
+    cell_size: f32,
+    dim_x: usize,
+    dim_y: usize,
+    // Entries of cell `i` are `entries[cell_starts[i]..cell_starts[i + 1]]`
+    cell_starts: Vec<usize>,
+    entries: Vec<(usize, Point2<f32>)>,
+}
+
+impl SpatialHash {
+    pub(crate) fn new() -> SpatialHash {
+        SpatialHash {
+            cell_size: 1.,
+            dim_x: 1,
+            dim_y: 1,
+            cell_starts: vec![0, 0],
+            entries: Vec::new(),
+        }
+    }
+
+    /// Bucket the indexed points of a `width` by `height` world, for queries up to `radius`.
+    ///
+    /// Points outside of the world are kept in the nearest cell on the edge.
+    pub(crate) fn rebuild<I>(&mut self, width: f32, height: f32, radius: f32, points: I)
+    where
+        I: Iterator<Item = (usize, Point2<f32>)> + Clone,
+    {
+        let per_side = MAX_CELLS_PER_SIDE as f32;
+        self.cell_size = radius.max(width / per_side).max(height / per_side).max(1.);
+        self.dim_x = ((width / self.cell_size).ceil() as usize).max(1);
+        self.dim_y = ((height / self.cell_size).ceil() as usize).max(1);
+
+        // Count the points in each cell, then hand out each cell its slice of the entries
+        let cell_count = self.dim_x * self.dim_y;
+        self.cell_starts.clear();
+        self.cell_starts.resize(cell_count + 1, 0);
+        for (_, point) in points.clone() {
+            let cell = self.cell_of(point);
+            self.cell_starts[cell + 1] += 1;
+        }
+        for cell in 0..cell_count {
+            self.cell_starts[cell + 1] += self.cell_starts[cell];
+        }
+
+        let mut next = self.cell_starts.clone();
+        self.entries.clear();
+        self.entries
+            .resize(self.cell_starts[cell_count], (0, Point2::new(0., 0.)));
+        for (index, point) in points {
+            let cell = self.cell_of(point);
+            self.entries[next[cell]] = (index, point);
+            next[cell] += 1;
+        }
+    }
+
+    /// Call `found` with the index of every point closer than `radius` to `centre`.
+    pub(crate) fn for_each_within<F>(&self, centre: Point2<f32>, radius: f32, mut found: F)
+    where
+        F: FnMut(usize),
+    {
+        let radius_2 = radius * radius;
+        let (min_x, min_y) = self.cell_coords(Point2::new(centre.x - radius, centre.y - radius));
+        let (max_x, max_y) = self.cell_coords(Point2::new(centre.x + radius, centre.y + radius));
+        for y in min_y..=max_y {
+            for x in min_x..=max_x {
+                let cell = x + y * self.dim_x;
+                let entries = &self.entries[self.cell_starts[cell]..self.cell_starts[cell + 1]];
+                for &(index, point) in entries {
+                    if (point - centre).magnitude2() < radius_2 {
+                        found(index);
+                    }
+                }
+            }
+        }
+    }
+
+    fn cell_of(&self, point: Point2<f32>) -> usize {
+        let (x, y) = self.cell_coords(point);
+        x + y * self.dim_x
+    }
+
+    fn cell_coords(&self, point: Point2<f32>) -> (usize, usize) {
+        // Casting saturates, so negative coordinates land in the first cell
+        let x = ((point.x / self.cell_size) as usize).min(self.dim_x - 1);
+        let y = ((point.y / self.cell_size) as usize).min(self.dim_y - 1);
+        (x, y)
+    }
+}
@@ -0,0 +1,197 @@
+use cgmath::{InnerSpace, Vector2};
+
+type Velocity = Vector2<f32>;
+type Force = Vector2<f32>;
+
+/// The parameters for one kind of boid.
+///
+/// Speeds are in world units per second, forces in world units per second squared.
+#[derive(Clone, Debug)]
+pub struct SpeciesConfig {
+    pub name: String,
+    /// Relative share of the flock, compared to the shares of the other species.
+    pub share: f32,
+    pub max_speed: f32,
+    pub max_force: f32,
+    pub sep_weight: f32,
+    pub ali_weight: f32,
+    pub coh_weight: f32,
+    pub sep_radius: f32,
+    pub ali_radius: f32,
+    pub coh_radius: f32,
+    /// Drawn coloured by heading when not given.
+    pub colour: Option<(f32, f32, f32)>,
+}
+
+/// How strongly each species reacts to each other species.
+///
+/// Row `a`, column `b` of a matrix scales how species `a` separates from, aligns with or
+/// coheres to species `b`. Missing entries count as 1, negative ones reverse the urge.
+#[derive(Clone, Debug, Default)]
+pub struct Interactions {
+    pub separation: Vec<Vec<f32>>,
+    pub alignment: Vec<Vec<f32>>,
+    pub cohesion: Vec<Vec<f32>>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct SpeciesConstants {
+    pub(crate) share: f32,
+    pub(crate) max_speed: f32,
+    pub(crate) max_force: f32,
+    pub(crate) sep_radius_2: f32,
+    pub(crate) ali_radius_2: f32,
+    pub(crate) coh_radius_2: f32,
+    pub(crate) sep_weight: f32,
+    pub(crate) ali_weight: f32,
+    pub(crate) coh_weight: f32,
+}
+
+impl SpeciesConstants {
+    pub(crate) fn from_config(conf: &SpeciesConfig) -> Self {
+        SpeciesConstants {
+            share: conf.share,
+            max_speed: conf.max_speed,
+            max_force: conf.max_force,
+            sep_radius_2: conf.sep_radius.powi(2),
+            ali_radius_2: conf.ali_radius.powi(2),
+            coh_radius_2: conf.coh_radius.powi(2),
+            sep_weight: conf.sep_weight,
+            ali_weight: conf.ali_weight,
+            coh_weight: conf.coh_weight,
+        }
+    }
+
+    // The furthest away any neighbour has an effect
+    pub(crate) fn max_radius(&self) -> f32 {
+        self.max_radius_2().sqrt()
+    }
+
+    pub(crate) fn max_radius_2(&self) -> f32 {
+        self.sep_radius_2
+            .max(self.ali_radius_2)
+            .max(self.coh_radius_2)
+    }
+
+    // The force needed to reach the target velocity within a single step, capped at max force
+    pub(crate) fn steer(&self, target: Velocity, current: Velocity, dt: f32) -> Force {
+        let force = (target - current) / dt;
+        if force.magnitude2() > self.max_force * self.max_force {
+            force.normalize_to(self.max_force)
+        } else {
+            force
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct Interaction {
+    pub(crate) separation: f32,
+    pub(crate) alignment: f32,
+    pub(crate) cohesion: f32,
+}
+
+/// Flatten the interaction matrices for `count` species into one entry per pair, row major.
+pub(crate) fn interaction_table(interactions: &Interactions, count: usize) -> Vec<Interaction> {
+    let entry = |matrix: &[Vec<f32>], a: usize, b: usize| {
+        matrix
+            .get(a)
+            .and_then(|row| row.get(b))
+            .copied()
+            .unwrap_or(1.)
+    };
+    (0..count * count)
+        .map(|i| {
+            let (a, b) = (i / count, i % count);
+            Interaction {
+                separation: entry(&interactions.separation, a, b),
+                alignment: entry(&interactions.alignment, a, b),
+                cohesion: entry(&interactions.cohesion, a, b),
+            }
+        })
+        .collect()
+}
+
+/// Split `boid_count` boids between the species in proportion to their shares.
+pub(crate) fn assign_species(species: &[SpeciesConfig], boid_count: usize) -> Vec<u32> {
+    let total: f32 = species.iter().map(|s| s.share.max(0.)).sum();
+    let mut assigned = Vec::with_capacity(boid_count);
+    let mut cumulative = 0.;
+    for (id, s) in species.iter().enumerate() {
+        cumulative += if total > 0. {
+            s.share.max(0.) / total
+        } else {
+            1. / species.len() as f32
+        };
+        let end = if id + 1 == species.len() {
+            boid_count
+        } else {
+            ((cumulative * boid_count as f32).round() as usize).min(boid_count)
+        };
+        while assigned.len() < end {
+            assigned.push(id as u32);
+        }
+    }
+    assigned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boids::SimulationConfig;
+
+    fn species(shares: &[f32]) -> Vec<SpeciesConfig> {
+        let base = SimulationConfig::default().species().remove(0);
+        shares
+            .iter()
+            .map(|&share| SpeciesConfig {
+                share,
+                ..base.clone()
+            })
+            .collect()
+    }
+
+    // How many boids each species was given
+    fn counts(shares: &[f32], boid_count: usize) -> Vec<usize> {
+        let ids = assign_species(&species(shares), boid_count);
+        assert_eq!(ids.len(), boid_count);
+        (0..shares.len())
+            .map(|id| ids.iter().filter(|&&s| s as usize == id).count())
+            .collect()
+    }
+
+    #[test]
+    fn boids_are_split_in_proportion_to_the_shares() {
+        assert_eq!(counts(&[1., 3.], 100), [25, 75]);
+        assert_eq!(counts(&[2., 1., 1.], 10), [5, 3, 2]);
+        assert_eq!(counts(&[1., 1., 1.], 10).iter().sum::<usize>(), 10);
+        assert_eq!(counts(&[0.5], 7), [7]);
+    }
+
+    #[test]
+    fn species_without_a_share_get_no_boids() {
+        assert_eq!(counts(&[0., 2., 0.], 9), [0, 9, 0]);
+        assert_eq!(counts(&[-1., 1.], 4), [0, 4]);
+    }
+
+    #[test]
+    fn without_any_shares_the_boids_are_split_evenly() {
+        assert_eq!(counts(&[0., 0.], 10), [5, 5]);
+    }
+
+    #[test]
+    fn missing_interactions_count_as_1() {
+        let interactions = Interactions {
+            separation: vec![vec![0.5]],
+            alignment: vec![vec![2.], vec![3., 4.]],
+            cohesion: Vec::new(),
+        };
+        let table = interaction_table(&interactions, 2);
+        let separation: Vec<_> = table.iter().map(|i| i.separation).collect();
+        let alignment: Vec<_> = table.iter().map(|i| i.alignment).collect();
+        let cohesion: Vec<_> = table.iter().map(|i| i.cohesion).collect();
+        assert_eq!(separation, [0.5, 1., 1., 1.]);
+        assert_eq!(alignment, [2., 1., 3., 4.]);
+        assert_eq!(cohesion, [1., 1., 1., 1.]);
+    }
+}
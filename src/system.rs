@@ -1,72 +1,242 @@
 use std::f32::consts::PI;
+use std::io::{Read, Write};
 
 use cgmath::{Basis2, InnerSpace, Point2, Rad, Rotation, Rotation2, Vector2};
 
 use rand::prelude::*;
+use rand_chacha::ChaCha8Rng;
+
+use crate::executor::Executor;
+use crate::grid::{BoidGrid, GridPtr};
+use crate::kernel::{KernelTables, NeighbourKernel};
+use crate::lanes::NeighbourLanes;
+use crate::obstacle::Obstacle;
+use crate::predator::{ChaseStrategy, Predator, PredatorConfig};
+use crate::snapshot::{self, SnapshotError};
+use crate::spatial_hash::SpatialHash;
+use crate::species::{self, Interaction, Interactions, SpeciesConfig, SpeciesConstants};
+
+pub use crate::grid::Boid;
 
 type Position = Point2<f32>;
 type Velocity = Vector2<f32>;
 type Force = Vector2<f32>;
 
 const TWO_PI: f32 = 2. * PI;
-const SHELL_GAPS: [usize; 9] = [1750, 701, 301, 132, 57, 23, 10, 4, 1];
+// Ciura's gaps, carried on by the usual factor of 2.25 for grids bigger than they cover
+const SHELL_GAPS: [usize; 9] = [1, 4, 10, 23, 57, 132, 301, 701, 1750];
+const SHELL_GAP_GROWTH: f32 = 2.25;
+
+/// What happens to boids when they reach the edge of the world.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Boundary {
+    /// Leave one edge and come back in the opposite one.
+    Wrap,
+    /// Reflect off the edges.
+    Bounce,
+    /// Steer away from the edges when within the boundary margin.
+    SoftWalls,
+    /// Fly off, to be replaced by a new boid entering from a random edge.
+    Open,
+}
+
+/// What happens to the world when the window changes size.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResizeMode {
+    /// Scale everything in the world to fit the new size.
+    Stretch,
+    /// Keep world units as they are, revealing or hiding the edges of the world.
+    Extend,
+}
+
+/// How much of the shell sort runs each step.
+///
+/// Boids barely move between steps, so the grid stays nearly sorted and the passes with the
+/// smallest gaps do most of the useful work.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortMode {
+    /// Every pass, from the biggest gap that fits the grid down.
+    Full,
+    /// Only the passes with the smallest few gaps.
+    Last,
+    /// As many of the last passes as it takes to keep the grid sorted to the target.
+    Adaptive,
+}
+
+/// Which boids work out their forces afresh on a step that only updates some of them.
+///
+/// The rest carry on with the force they last worked out.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForcePartition {
+    /// A band of grid rows, moving down the grid a band at a time.
+    Rows,
+    /// Cells spread evenly through the grid, moving one cell further along each step.
+    Cells,
+}
+
+/// How each boid finds the neighbours it flocks with.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NeighbourSearch {
+    /// Look in a few cells of the partially sorted grid, in the direction the boid is heading.
+    Grid,
+    /// Find every boid within range, however far away in the grid. Much slower.
+    Exact,
+}
+
+/// How well the approximate neighbour grid matches an exact search, over one step.
+///
+/// A neighbour is any other boid within the largest radius of the boid's species, and the
+/// force compared is the one from flocking with neighbours alone.
+#[derive(Clone, Debug, Default)]
+pub struct NeighbourComparison {
+    pub boids: usize,
+    /// Neighbours there are to be found.
+    pub true_neighbours: usize,
+    /// Boids the grid looked at.
+    pub candidates: usize,
+    /// Boids the grid looked at that really were neighbours.
+    pub found: usize,
+    /// Sum over boids of the magnitude of the difference between the two forces.
+    pub force_error: f32,
+    /// Sum over boids of the magnitude of the exact force.
+    pub exact_force: f32,
+}
+
+impl NeighbourComparison {
+    /// Fold in the counts from another comparison, to summarise several steps together.
+    pub fn add(&mut self, other: &NeighbourComparison) {
+        self.boids += other.boids;
+        self.true_neighbours += other.true_neighbours;
+        self.candidates += other.candidates;
+        self.found += other.found;
+        self.force_error += other.force_error;
+        self.exact_force += other.exact_force;
+    }
+
+    /// The share of true neighbours the grid found.
+    pub fn recall(&self) -> f32 {
+        ratio(self.found, self.true_neighbours)
+    }
+
+    /// The share of boids the grid looked at that were neighbours.
+    pub fn precision(&self) -> f32 {
+        ratio(self.found, self.candidates)
+    }
+
+    pub fn mean_force_error(&self) -> f32 {
+        self.force_error / self.boids.max(1) as f32
+    }
+
+    /// Force error relative to the size of the exact forces.
+    pub fn relative_force_error(&self) -> f32 {
+        if self.exact_force > 0. {
+            self.force_error / self.exact_force
+        } else {
+            0.
+        }
+    }
+}
 
+/// Speeds are in world units per second, forces in world units per second squared.
 pub struct FlockingConfig {
     pub boid_count: u32,
     pub width: f32,
     pub height: f32,
-    pub max_speed: f32,
-    pub max_force: f32,
+    /// At least one species, boids are shared out between them.
+    pub species: Vec<SpeciesConfig>,
+    pub interactions: Interactions,
     pub mouse_weight: f32,
-    pub sep_weight: f32,
-    pub ali_weight: f32,
-    pub coh_weight: f32,
-    pub sep_radius: f32,
-    pub ali_radius: f32,
-    pub coh_radius: f32,
+    pub avoid_weight: f32,
+    pub avoid_radius: f32,
+    pub obstacles: Vec<Obstacle>,
+    pub boundary: Boundary,
+    pub boundary_margin: f32,
+    pub resize: ResizeMode,
+    pub neighbours: NeighbourSearch,
+    pub sort: SortMode,
+    /// Passes run by the last sort mode, and the first guess of the adaptive one.
+    pub sort_passes: usize,
+    /// Share of neighbouring cells the adaptive sort keeps in order.
+    pub sort_target: f32,
+    pub kernel: NeighbourKernel,
+    /// How many cells a sampling kernel looks in.
+    pub kernel_samples: usize,
+    /// Field of view in degrees, neighbours outside of it are ignored.
+    ///
+    /// The `Forward` kernel looks mostly behind the boid, so pairs badly with a narrow one.
+    pub fov: f32,
+    /// Share of boids that work out their forces afresh each step.
+    pub force_update_fraction: f32,
+    pub force_partition: ForcePartition,
+    pub predators: PredatorConfig,
+    pub threads: usize,
+    /// Picked at random when not given.
+    pub seed: Option<u64>,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 struct FlockingConstants {
-    max_speed: f32,
-    max_force: f32,
+    species: Vec<SpeciesConstants>,
+    interactions: Vec<Interaction>,
     mouse_weight: f32,
-    sep_radius_2: f32,
-    ali_radius_2: f32,
-    coh_radius_2: f32,
-    sep_weight: f32,
-    ali_weight: f32,
-    coh_weight: f32,
+    avoid_weight: f32,
+    avoid_radius: f32,
+    boundary: Boundary,
+    boundary_margin: f32,
+    resize: ResizeMode,
+    neighbours: NeighbourSearch,
+    sort: SortMode,
+    sort_passes: usize,
+    sort_target: f32,
+    kernel: NeighbourKernel,
+    kernel_samples: usize,
+    fov: f32,
+    force_update_fraction: f32,
+    force_partition: ForcePartition,
+    predators: PredatorConfig,
 }
 
 impl FlockingConstants {
-    fn from_config(conf: FlockingConfig) -> Self {
+    fn from_config(conf: &FlockingConfig) -> Self {
         FlockingConstants {
-            max_speed: conf.max_speed,
-            max_force: conf.max_force,
+            species: conf
+                .species
+                .iter()
+                .map(SpeciesConstants::from_config)
+                .collect(),
+            interactions: species::interaction_table(&conf.interactions, conf.species.len()),
             mouse_weight: conf.mouse_weight,
-            sep_radius_2: conf.sep_radius.powi(2),
-            ali_radius_2: conf.ali_radius.powi(2),
-            coh_radius_2: conf.coh_radius.powi(2),
-            sep_weight: conf.sep_weight,
-            ali_weight: conf.ali_weight,
-            coh_weight: conf.coh_weight,
+            avoid_weight: conf.avoid_weight,
+            avoid_radius: conf.avoid_radius,
+            boundary: conf.boundary,
+            boundary_margin: conf.boundary_margin,
+            resize: conf.resize,
+            neighbours: conf.neighbours,
+            sort: conf.sort,
+            sort_passes: conf.sort_passes,
+            sort_target: conf.sort_target,
+            kernel: conf.kernel,
+            kernel_samples: conf.kernel_samples,
+            fov: conf.fov,
+            force_update_fraction: conf.force_update_fraction,
+            force_partition: conf.force_partition,
+            predators: conf.predators.clone(),
         }
     }
-}
 
-#[repr(C)]
-#[derive(Clone)]
-pub struct Boid {
-    position: Position,
-    velocity: Velocity,
-}
+    fn species(&self, species: u32) -> &SpeciesConstants {
+        &self.species[species as usize]
+    }
 
-impl Boid {
-    fn new() -> Self {
-        Boid {
-            position: Position::new(0., 0.),
-            velocity: Velocity::new(0., 0.),
-        }
+    // How a boid of one species reacts to a boid of the other
+    fn interaction(&self, species: u32, other: u32) -> Interaction {
+        let index = species as usize * self.species.len() + other as usize;
+        self.interactions[index]
     }
 }
 
@@ -75,12 +245,45 @@ pub struct FlockingSystem {
     height: f32,
     dim_x: usize,
     dim_y: usize,
-    boid_grid: Vec<Boid>,
-    forces: Vec<Force>,
+    boid_count: usize,
+    boid_grid: BoidGrid,
+    // Forces worked out this step, before they are handed to the boids
+    fresh_forces: Vec<Force>,
     params: FlockingConstants,
+    obstacles: Vec<Obstacle>,
+    predators: Vec<Predator>,
     mouse_position: Position,
     mouse_multiplier: f32,
-    rng: ThreadRng,
+    steps: u64,
+    seed: u64,
+    rng: ChaCha8Rng,
+    // Passes of the shell sort made each step, as last chosen by the sort mode
+    sort_passes: usize,
+    executor: Executor,
+    spatial_hash: SpatialHash,
+    // Worked out from the flocking parameters, whenever they change
+    kernel: KernelTables,
+    fov_cos: f32,
+}
+
+// Everything needed to carry on the simulation exactly where it was left off
+#[derive(Serialize, Deserialize)]
+struct FlockState {
+    width: f32,
+    height: f32,
+    dim_x: usize,
+    dim_y: usize,
+    boid_count: usize,
+    boid_grid: BoidGrid,
+    params: FlockingConstants,
+    obstacles: Vec<Obstacle>,
+    predators: Vec<Predator>,
+    mouse_position: Position,
+    mouse_multiplier: f32,
+    steps: u64,
+    seed: u64,
+    rng: ChaCha8Rng,
+    sort_passes: usize,
 }
 
 impl FlockingSystem {
@@ -88,22 +291,43 @@ impl FlockingSystem {
         // TODO: conf.grid_size()
         let (dim_x, dim_y) = grid_size(conf.width, conf.height, conf.boid_count);
         let grid_capacity = dim_x * dim_y;
+        let boid_count = conf.boid_count as usize;
+        let seed = conf.seed.unwrap_or_else(|| thread_rng().gen());
 
-        // TODO: Use sentinal values so boid count can be exactly as requested
-        // Could have a sentinal boid at position 0
-        let boid_count = grid_capacity;
+        let mut species = species::assign_species(&conf.species, boid_count).into_iter();
+        let boid_grid = (0..grid_capacity)
+            .map(|_| match species.next() {
+                Some(id) => Boid::new(id),
+                None => Boid::sentinel(),
+            })
+            .collect();
+
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        let predators = (0..conf.predators.count)
+            .map(|_| spawn_predator(&mut rng, &conf.predators, conf.width, conf.height))
+            .collect();
 
         FlockingSystem {
             width: conf.width,
             height: conf.height,
             dim_x,
             dim_y,
-            boid_grid: (0..boid_count).map(|_| Boid::new()).collect(),
-            forces: vec![Force::new(0., 0.); boid_count],
-            params: FlockingConstants::from_config(conf),
+            boid_count,
+            boid_grid,
+            fresh_forces: vec![Force::new(0., 0.); grid_capacity],
+            executor: Executor::new(conf.threads),
+            spatial_hash: SpatialHash::new(),
+            kernel: KernelTables::new(conf.kernel, conf.kernel_samples),
+            fov_cos: fov_cos(conf.fov),
+            params: FlockingConstants::from_config(&conf),
+            obstacles: conf.obstacles,
+            predators,
             mouse_position: Position::new(0., 0.),
             mouse_multiplier: 1.,
-            rng: thread_rng(),
+            steps: 0,
+            seed,
+            rng,
+            sort_passes: conf.sort_passes.max(1),
         }
     }
 
@@ -114,24 +338,109 @@ impl FlockingSystem {
 
     pub fn centralise(&mut self) {
         let center = Position::new(self.width / 2., self.height / 2.);
-        for boid in &mut self.boid_grid {
-            boid.position = center
+        for position in self.boid_grid.boid_positions_mut() {
+            *position = center
         }
         self.randomise_velocities();
     }
 
     pub fn zeroise(&mut self) {
-        for boid in &mut self.boid_grid {
-            boid.position = Position::new(0., 0.);
+        for position in self.boid_grid.boid_positions_mut() {
+            *position = Position::new(0., 0.);
         }
         self.randomise_velocities();
     }
 
-    // TODO: Supply a time delta to update so simulation can be frame independant
-    pub fn update(&mut self) {
+    /// Advance the simulation by `dt` seconds.
+    pub fn update(&mut self, dt: f32) {
         self.sort_boids();
-        self.calculate_forces();
-        self.update_boids();
+        self.calculate_forces(dt);
+        self.update_boids(dt);
+        self.update_predators(dt);
+        self.steps += 1;
+    }
+
+    /// Take on new flocking parameters without losing the flock.
+    ///
+    /// The number of boids and the size of the world stay as they are.
+    pub fn reconfigure(&mut self, conf: FlockingConfig) {
+        let species_changed = conf.species.len() != self.params.species.len()
+            || conf
+                .species
+                .iter()
+                .zip(&self.params.species)
+                .any(|(s, constants)| s.share != constants.share);
+        if species_changed {
+            // Shuffled, as handing out species in grid order would split them up by position
+            let mut ids = species::assign_species(&conf.species, self.boid_count);
+            ids.shuffle(&mut self.rng);
+            let grid = &mut self.boid_grid;
+            let boids: Vec<usize> = grid.boid_indexes().collect();
+            for (index, id) in boids.into_iter().zip(ids) {
+                grid.species[index] = id;
+            }
+        }
+
+        self.params = FlockingConstants::from_config(&conf);
+        self.match_predator_count();
+        self.refresh_kernel();
+        self.obstacles = conf.obstacles;
+        // Starting a thread pool is slow, so only done when the thread count changes
+        if conf.threads != self.executor.threads() {
+            self.executor = Executor::new(conf.threads);
+        }
+    }
+
+    /// Change the size of the world without losing the flock, according to the resize mode.
+    pub fn resize(&mut self, width: f32, height: f32) {
+        match self.params.resize {
+            ResizeMode::Stretch => {
+                let (sx, sy) = (width / self.width, height / self.height);
+                let stretch = |p: &mut Position| {
+                    p.x *= sx;
+                    p.y *= sy;
+                };
+                for position in self.boid_grid.boid_positions_mut() {
+                    stretch(position);
+                }
+                for predator in &mut self.predators {
+                    stretch(&mut predator.position);
+                }
+                for obstacle in &mut self.obstacles {
+                    obstacle.scale(sx, sy);
+                }
+                stretch(&mut self.mouse_position);
+            }
+            ResizeMode::Extend => {
+                // Anything left outside a shrunken world comes back in on the other side
+                let fold = |p: &mut Position| {
+                    p.x = p.x.rem_euclid(width);
+                    p.y = p.y.rem_euclid(height);
+                };
+                for position in self.boid_grid.boid_positions_mut() {
+                    fold(position);
+                }
+                for predator in &mut self.predators {
+                    fold(&mut predator.position);
+                }
+            }
+        }
+        self.width = width;
+        self.height = height;
+        self.regrid();
+    }
+
+    pub fn width(&self) -> f32 {
+        self.width
+    }
+
+    pub fn height(&self) -> f32 {
+        self.height
+    }
+
+    /// The number of updates made since the flock was created.
+    pub fn steps(&self) -> u64 {
+        self.steps
     }
 
     pub fn set_mouse(&mut self, x: f32, y: f32) {
@@ -146,122 +455,451 @@ impl FlockingSystem {
         self.mouse_multiplier = 1.;
     }
 
-    pub fn boids(&self) -> &[Boid] {
-        &self.boid_grid
+    // Setters for the flocking parameters. Those of a single species take its index, in the
+    // order the species were configured, and panic if there is no such species.
+
+    pub fn set_max_speed(&mut self, species: usize, max_speed: f32) {
+        self.params.species[species].max_speed = max_speed;
+    }
+
+    pub fn set_max_force(&mut self, species: usize, max_force: f32) {
+        self.params.species[species].max_force = max_force;
+    }
+
+    pub fn set_sep_weight(&mut self, species: usize, weight: f32) {
+        self.params.species[species].sep_weight = weight;
+    }
+
+    pub fn set_ali_weight(&mut self, species: usize, weight: f32) {
+        self.params.species[species].ali_weight = weight;
+    }
+
+    pub fn set_coh_weight(&mut self, species: usize, weight: f32) {
+        self.params.species[species].coh_weight = weight;
+    }
+
+    pub fn set_sep_radius(&mut self, species: usize, radius: f32) {
+        self.params.species[species].sep_radius_2 = radius.powi(2);
+    }
+
+    pub fn set_ali_radius(&mut self, species: usize, radius: f32) {
+        self.params.species[species].ali_radius_2 = radius.powi(2);
+    }
+
+    pub fn set_coh_radius(&mut self, species: usize, radius: f32) {
+        self.params.species[species].coh_radius_2 = radius.powi(2);
+    }
+
+    pub fn set_interactions(&mut self, interactions: &Interactions) {
+        let count = self.params.species.len();
+        self.params.interactions = species::interaction_table(interactions, count);
+    }
+
+    pub fn set_mouse_weight(&mut self, weight: f32) {
+        self.params.mouse_weight = weight;
+    }
+
+    pub fn set_avoid_weight(&mut self, weight: f32) {
+        self.params.avoid_weight = weight;
+    }
+
+    pub fn set_avoid_radius(&mut self, radius: f32) {
+        self.params.avoid_radius = radius;
+    }
+
+    pub fn set_boundary(&mut self, boundary: Boundary) {
+        self.params.boundary = boundary;
+    }
+
+    pub fn set_boundary_margin(&mut self, margin: f32) {
+        self.params.boundary_margin = margin;
+    }
+
+    pub fn set_resize_mode(&mut self, resize: ResizeMode) {
+        self.params.resize = resize;
+    }
+
+    pub fn set_neighbour_search(&mut self, neighbours: NeighbourSearch) {
+        self.params.neighbours = neighbours;
+    }
+
+    pub fn set_kernel(&mut self, kernel: NeighbourKernel, samples: usize) {
+        self.params.kernel = kernel;
+        self.params.kernel_samples = samples;
+        self.refresh_kernel();
+    }
+
+    pub fn set_fov(&mut self, fov: f32) {
+        self.params.fov = fov;
+        self.refresh_kernel();
+    }
+
+    /// Work out afresh the forces of `fraction` of the boids each step, picked by `partition`.
+    pub fn set_force_updates(&mut self, fraction: f32, partition: ForcePartition) {
+        self.params.force_update_fraction = fraction;
+        self.params.force_partition = partition;
+    }
+
+    /// Predators are added or removed to match the new count, the rest carry on as they were.
+    pub fn set_predators(&mut self, predators: PredatorConfig) {
+        self.params.predators = predators;
+        self.match_predator_count();
+    }
+
+    fn refresh_kernel(&mut self) {
+        self.kernel = KernelTables::new(self.params.kernel, self.params.kernel_samples);
+        self.fov_cos = fov_cos(self.params.fov);
+    }
+
+    fn match_predator_count(&mut self) {
+        let conf = &self.params.predators;
+        let count = conf.count as usize;
+        self.predators.truncate(count);
+        while self.predators.len() < count {
+            let predator = spawn_predator(&mut self.rng, conf, self.width, self.height);
+            self.predators.push(predator);
+        }
+    }
+
+    pub fn save_snapshot<W: Write>(&self, writer: W) -> Result<(), SnapshotError> {
+        let state = FlockState {
+            width: self.width,
+            height: self.height,
+            dim_x: self.dim_x,
+            dim_y: self.dim_y,
+            boid_count: self.boid_count,
+            boid_grid: self.boid_grid.clone(),
+            params: self.params.clone(),
+            obstacles: self.obstacles.clone(),
+            predators: self.predators.clone(),
+            mouse_position: self.mouse_position,
+            mouse_multiplier: self.mouse_multiplier,
+            steps: self.steps,
+            seed: self.seed,
+            rng: self.rng.clone(),
+            sort_passes: self.sort_passes,
+        };
+        snapshot::write(writer, &state)
+    }
+
+    /// Replace the whole state of the flock with a snapshot, leaving it untouched on error.
+    pub fn load_snapshot<R: Read>(&mut self, reader: R) -> Result<(), SnapshotError> {
+        let state: FlockState = snapshot::read(reader)?;
+        if state.dim_x == 0 || state.dim_y == 0 {
+            return Err(SnapshotError::Invalid(String::from("grid has no cells")));
+        }
+        if !state.boid_grid.is_consistent() || state.boid_grid.len() != state.dim_x * state.dim_y {
+            return Err(SnapshotError::Invalid(String::from(
+                "grid does not match its dimensions",
+            )));
+        }
+        if state.boid_grid.boid_indexes().count() != state.boid_count {
+            return Err(SnapshotError::Invalid(String::from(
+                "grid does not hold the recorded number of boids",
+            )));
+        }
+        let species_count = state.params.species.len();
+        if state.params.interactions.len() != species_count * species_count
+            || state
+                .boid_grid
+                .species
+                .iter()
+                .any(|&s| s as usize >= species_count)
+        {
+            return Err(SnapshotError::Invalid(String::from(
+                "boids do not match the recorded species",
+            )));
+        }
+
+        self.width = state.width;
+        self.height = state.height;
+        self.dim_x = state.dim_x;
+        self.dim_y = state.dim_y;
+        self.boid_count = state.boid_count;
+        self.fresh_forces = vec![Force::new(0., 0.); state.boid_grid.len()];
+        self.boid_grid = state.boid_grid;
+        self.params = state.params;
+        self.obstacles = state.obstacles;
+        self.predators = state.predators;
+        self.mouse_position = state.mouse_position;
+        self.mouse_multiplier = state.mouse_multiplier;
+        self.steps = state.steps;
+        self.seed = state.seed;
+        self.rng = state.rng;
+        self.sort_passes = state.sort_passes;
+        self.refresh_kernel();
+        Ok(())
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn add_obstacle(&mut self, obstacle: Obstacle) {
+        self.obstacles.push(obstacle);
+    }
+
+    pub fn obstacles(&self) -> &[Obstacle] {
+        &self.obstacles
+    }
+
+    pub fn predators(&self) -> &[Predator] {
+        &self.predators
+    }
+
+    pub fn boid_count(&self) -> usize {
+        self.boid_count
+    }
+
+    pub fn boids(&self) -> impl Iterator<Item = Boid> + '_ {
+        let grid = &self.boid_grid;
+        grid.boid_indexes().map(|index| grid.get(index))
+    }
+
+    /// Positions in the raw grid, including those of the sentinel boids used to pad out unused
+    /// cells, which are at infinity.
+    pub fn grid_positions(&self) -> &[Point2<f32>] {
+        &self.boid_grid.positions
+    }
+
+    /// Velocities in the raw grid, in the same order as the positions.
+    pub fn grid_velocities(&self) -> &[Vector2<f32>] {
+        &self.boid_grid.velocities
+    }
+
+    /// Species in the raw grid, in the same order as the positions.
+    pub fn grid_species(&self) -> &[u32] {
+        &self.boid_grid.species
+    }
+
+    // Fit the grid to the shape of the world, the next sort puts the boids back in order
+    fn regrid(&mut self) {
+        let (dim_x, dim_y) = grid_size(self.width, self.height, self.boid_count as u32);
+        let grid_capacity = dim_x * dim_y;
+        self.boid_grid.repack(grid_capacity);
+        self.fresh_forces = vec![Force::new(0., 0.); grid_capacity];
+        self.dim_x = dim_x;
+        self.dim_y = dim_y;
     }
 
     fn randomise_positions(&mut self) {
-        for boid in &mut self.boid_grid {
+        for position in self.boid_grid.boid_positions_mut() {
             let x = self.rng.gen_range(0f32..self.width);
             let y = self.rng.gen_range(0f32..self.height);
-            boid.position = Point2::new(x, y);
+            *position = Point2::new(x, y);
         }
     }
 
     fn randomise_velocities(&mut self) {
-        for boid in &mut self.boid_grid {
-            let a = self.rng.gen_range(0f32..self.params.max_speed);
-            let m = self.rng.gen_range(0f32..TWO_PI);
-            boid.velocity = velocity_from_polar(a, m);
+        let grid = &mut self.boid_grid;
+        for index in 0..grid.len() {
+            if grid.is_sentinel(index) {
+                continue;
+            }
+            let a = self.rng.gen_range(0f32..TWO_PI);
+            let max_speed = self.params.species(grid.species[index]).max_speed;
+            let m = self.rng.gen_range(0f32..max_speed);
+            grid.velocities[index] = velocity_from_polar(a, m);
         }
     }
 
     fn sort_boids(&mut self) {
-        //TODO: Could we pick the right starting gap such that we dont need these checks?
-        for &gap in SHELL_GAPS.iter() {
-            if gap < self.dim_x {
+        let row_gaps = shell_gaps(self.dim_x);
+        let col_gaps = shell_gaps(self.dim_y);
+        let passes = match self.params.sort {
+            SortMode::Full => row_gaps.len().max(col_gaps.len()),
+            SortMode::Last => self.params.sort_passes,
+            SortMode::Adaptive => self.sort_passes,
+        };
+        // Rows and columns take turns, each going from its biggest chosen gap down to 1
+        for pass in (0..passes).rev() {
+            if let Some(&gap) = row_gaps.get(pass) {
                 self.spatial_shell_pass_rows(gap);
             }
-            if gap < self.dim_y {
+            if let Some(&gap) = col_gaps.get(pass) {
                 self.spatial_shell_pass_columns(gap);
             }
         }
+        self.sort_passes = passes;
+
+        // One more pass next time when the sort falls behind, one fewer when it has time to spare
+        if self.params.sort == SortMode::Adaptive {
+            let most = row_gaps.len().max(col_gaps.len()).max(1);
+            self.sort_passes = if self.sortedness() < self.params.sort_target {
+                (passes + 1).min(most)
+            } else {
+                passes.saturating_sub(1).max(1)
+            };
+        }
     }
 
-    fn spatial_shell_pass_rows(&mut self, gap: usize) {
-        for row in 0..self.dim_y {
-            for col in gap..self.dim_x {
-                let temp_boid = unsafe { self.query_boid_grid(col, row) }.clone();
-                let mut j = col;
-                while j >= gap {
-                    let curr_boid = unsafe { self.query_boid_grid(j - gap, row) };
-                    if curr_boid.position.x < temp_boid.position.x {
-                        unsafe {
-                            self.update_boid_grid(j, row, curr_boid.clone());
-                        }
-                    } else {
-                        break;
-                    }
-                    j -= gap;
-                }
-                if j != col {
-                    unsafe {
-                        self.update_boid_grid(j, row, temp_boid);
-                    }
-                }
+    /// The share of neighbouring cells in the grid that are in the order the shell sort leaves
+    /// them, by x along rows and by y down columns. Neighbours are searched for as if this were 1.
+    pub fn sortedness(&self) -> f32 {
+        let (dim_x, dim_y) = (self.dim_x, self.dim_y);
+        let positions = &self.boid_grid.positions;
+        let mut ordered = 0;
+        for row in positions.chunks(dim_x) {
+            ordered += row.windows(2).filter(|w| w[0].x >= w[1].x).count();
+        }
+        for i in 0..dim_x * (dim_y.saturating_sub(1)) {
+            if positions[i].y >= positions[i + dim_x].y {
+                ordered += 1;
             }
         }
+        let pairs = dim_y * dim_x.saturating_sub(1) + dim_x * dim_y.saturating_sub(1);
+        ratio(ordered, pairs)
+    }
+
+    /// How many passes the shell sort is making over the rows and columns each update.
+    pub fn sort_passes(&self) -> usize {
+        self.sort_passes
     }
 
+    // Every row can be sorted independently of the others
+    fn spatial_shell_pass_rows(&mut self, gap: usize) {
+        let dim_x = self.dim_x;
+        let grid = self.boid_grid.ptr();
+        self.executor.for_each_index(self.dim_y, |row| unsafe {
+            shell_pass(grid, row * dim_x, dim_x, 1, gap, |p| p.x)
+        });
+    }
+
+    // Every column can be sorted independently of the others
     fn spatial_shell_pass_columns(&mut self, gap: usize) {
-        for col in 0..self.dim_x {
-            for row in gap..self.dim_y {
-                let temp_boid = unsafe { self.query_boid_grid(col, row) }.clone();
-                let mut j = row;
-                while j >= gap {
-                    let curr_boid = unsafe { self.query_boid_grid(col, j - gap) };
-                    if curr_boid.position.y < temp_boid.position.y {
-                        unsafe {
-                            self.update_boid_grid(col, j, curr_boid.clone());
-                        }
-                    } else {
-                        break;
-                    }
-                    j -= gap;
-                }
-                if j != row {
-                    unsafe {
-                        self.update_boid_grid(col, j, temp_boid);
-                    }
-                }
-            }
-        }
+        let (dim_x, dim_y) = (self.dim_x, self.dim_y);
+        let grid = self.boid_grid.ptr();
+        self.executor.for_each_index(dim_x, |col| unsafe {
+            shell_pass(grid, col, dim_y, dim_x, gap, |p| p.y)
+        });
+    }
+
+    /// Measure how the neighbour grid compares with an exact search, on the flock as it is.
+    pub fn compare_neighbours(&mut self, dt: f32) -> NeighbourComparison {
+        // The grid is only meant to be searched once sorted, as the next update would sort it.
+        // Sorting a copy leaves the flock, and the adaptive sort, to carry on as they would have.
+        let (grid, sort_passes) = (self.boid_grid.clone(), self.sort_passes);
+        self.sort_boids();
+        self.rebuild_spatial_hash();
+        let comparison = self.measure_neighbours(dt);
+        self.boid_grid = grid;
+        self.sort_passes = sort_passes;
+        comparison
     }
 
-    //TODO: Try and lose these - replace with counter?
-    // could have iterators for row wise and column wise? (row, col, index)
-    #[inline(always)]
-    unsafe fn query_boid_grid(&self, column: usize, row: usize) -> &Boid {
-        self.boid_grid.get_unchecked(column + (row * self.dim_x))
+    // Search the grid as it is, and the spatial hash, for the neighbours of every boid
+    fn measure_neighbours(&self, dt: f32) -> NeighbourComparison {
+        let mut comparison = NeighbourComparison::default();
+        let (mut approx, mut exact) = (NeighbourLanes::new(), NeighbourLanes::new());
+        for index in self.boid_grid.boid_indexes() {
+            let (col, row) = (index % self.dim_x, index / self.dim_x);
+            let boid = self.boid_grid.get(index);
+            let radius_2 = self.params.species(boid.species).max_radius_2();
+            approx.clear();
+            self.find_neighbours(col, row, &boid, |other, position| {
+                approx.push(other, position)
+            });
+            self.gather(&boid, &mut approx);
+            exact.clear();
+            self.find_exact_neighbours(&boid, |other, position| {
+                if other != index {
+                    exact.push(other, position);
+                }
+            });
+            self.gather(&boid, &mut exact);
+
+            comparison.boids += 1;
+            comparison.true_neighbours += exact.len();
+            comparison.candidates += approx.len();
+            comparison.found += approx.count_within(boid.position, radius_2);
+            let approx_force = self.react_to_neighbours(index, &approx, dt);
+            let exact_force = self.react_to_neighbours(index, &exact, dt);
+            comparison.force_error += (approx_force - exact_force).magnitude();
+            comparison.exact_force += exact_force.magnitude();
+        }
+        comparison
     }
 
-    //TODO: As above
-    #[inline(always)]
-    unsafe fn update_boid_grid(&mut self, column: usize, row: usize, boid: Boid) {
-        *self
+    fn rebuild_spatial_hash(&mut self) {
+        let radius = self
+            .params
+            .species
+            .iter()
+            .map(SpeciesConstants::max_radius)
+            .fold(0., f32::max);
+        let points = self
             .boid_grid
-            .get_unchecked_mut(column + (row * self.dim_x)) = boid
+            .positions
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| !p.x.is_infinite())
+            .map(|(i, &p)| (i, p));
+        self.spatial_hash
+            .rebuild(self.width, self.height, radius, points);
     }
 
-    fn calculate_forces(&mut self) {
+    fn calculate_forces(&mut self, dt: f32) {
         //TODO: ROLLY THING
-        let mut neighbours = Vec::with_capacity(10); // FIXME: remove hardcoded
-        for row in 0..self.dim_y {
-            for col in 0..self.dim_x {
-                let boid_index = col + (row * self.dim_x);
-                let boid = unsafe { self.boid_grid.get_unchecked(boid_index) };
-                let mut force = Vector2::new(0., 0.);
-                neighbours.clear();
-                self.find_neighbours(col, row, boid, &mut neighbours);
-                let boid = boid.clone();
-                force += self.react_to_neighbours(&boid, &neighbours);
-                force += self.react_to_mouse(&boid);
-                unsafe { *self.forces.get_unchecked_mut(boid_index) = force };
+        let search = self.params.neighbours;
+        if search == NeighbourSearch::Exact {
+            self.rebuild_spatial_hash();
+        }
+        // Forces only depend on the grid, so each row can be worked out independently
+        let schedule = self.force_schedule();
+        let mut forces = std::mem::take(&mut self.fresh_forces);
+        self.executor
+            .for_each_chunk(&mut forces, self.dim_x, |row, row_forces| {
+                if !schedule.any_in_row(row) {
+                    return;
+                }
+                let mut neighbours = NeighbourLanes::new();
+                for (col, force) in row_forces.iter_mut().enumerate() {
+                    let index = col + (row * self.dim_x);
+                    if self.boid_grid.is_sentinel(index) || !schedule.includes(index, row) {
+                        continue;
+                    }
+                    neighbours.clear();
+                    let boid = self.boid_grid.get(index);
+                    let found = |other, position| neighbours.push(other, position);
+                    match search {
+                        NeighbourSearch::Grid => self.find_neighbours(col, row, &boid, found),
+                        NeighbourSearch::Exact => self.find_exact_neighbours(&boid, found),
+                    }
+                    // Those out of reach add nothing, so the rest of them need never be read
+                    let reach_2 = self.params.species(boid.species).max_radius_2();
+                    neighbours.keep_within(boid.position, reach_2);
+                    self.gather(&boid, &mut neighbours);
+                    *force = self.react_to_neighbours(index, &neighbours, dt);
+                    *force += self.react_to_mouse(&boid);
+                    *force += self.avoid_obstacles(&boid, dt);
+                    *force += self.avoid_walls(&boid, dt);
+                    *force += self.flee_predators(&boid, dt);
+                }
+            });
+        self.fresh_forces = forces;
+    }
+
+    // Which cells work out their forces afresh this step
+    fn force_schedule(&self) -> ForceSchedule {
+        let fraction = self.params.force_update_fraction.clamp(0., 1.);
+        let (dim_y, step) = (self.dim_y, self.steps as usize);
+        match self.params.force_partition {
+            ForcePartition::Rows => {
+                let band = ((dim_y as f32 * fraction).ceil() as usize).clamp(1, dim_y.max(1));
+                ForceSchedule::Rows {
+                    first: step.wrapping_mul(band) % dim_y.max(1),
+                    band,
+                    dim_y,
+                }
             }
+            ForcePartition::Cells => ForceSchedule::Cells {
+                step: self.steps,
+                fraction: fraction as f64,
+            },
         }
     }
 
-    fn react_to_mouse(&mut self, boid: &Boid) -> Force {
+    fn react_to_mouse(&self, boid: &Boid) -> Force {
         let from_mouse = boid.position - self.mouse_position;
         let dist_sq = from_mouse.magnitude2();
         if dist_sq > 0. {
@@ -272,148 +910,435 @@ impl FlockingSystem {
         }
     }
 
-    fn find_neighbours(&self, col: usize, row: usize, boid: &Boid, neighbourhood: &mut Vec<Boid>) {
-        //TODO: Could try other "kernals"
-        //TODO Remove use of i32, use usize instead
-
-        let v = boid.velocity;
-
-        // This is essentially a look up table to determine which flockmates the boid is facing
-        #[rustfmt::skip]
-        let neighbours = match (v.x > 0., v.y > 0., v.x.abs() > v.y.abs()) {
-            (true, true, true) => &[
-                (1, -1), (1, 0), (1, 1), (2, 0), (2, 1),
-                (0, 1), (2, 2), (0, -1), (2, -1), (1, 2),
-            ],
+    fn avoid_obstacles(&self, boid: &Boid, dt: f32) -> Force {
+        let mut away = Vector2::new(0., 0.);
+        for obstacle in &self.obstacles {
+            let (closest, inside) = obstacle.closest_point(boid.position);
+            let from_obstacle = boid.position - closest;
+            let dist = from_obstacle.magnitude();
+            if inside && dist > 0. {
+                // Already in trouble, head straight for the nearest way out
+                away -= from_obstacle / dist;
+            } else if dist > 0. && dist < self.params.avoid_radius {
+                away += from_obstacle / dist * (1. - dist / self.params.avoid_radius);
+            }
+        }
+        if away.magnitude2() > 0. {
+            let species = self.params.species(boid.species);
+            let target_vel = away.normalize_to(species.max_speed);
+            self.params.avoid_weight * species.steer(target_vel, boid.velocity, dt)
+        } else {
+            Force::new(0., 0.)
+        }
+    }
 
-            (true, true, false) => &[
-                (1, 1), (0, 1), (-1, 1), (0, 2), (1, 2),
-                (1, 0), (2, 2), (-1, 0), (2, 1), (-1, 2),
-            ],
+    fn avoid_walls(&self, boid: &Boid, dt: f32) -> Force {
+        if self.params.boundary != Boundary::SoftWalls {
+            return Force::new(0., 0.);
+        }
+        // Push inwards from any edge within the margin, harder the closer the boid gets
+        let margin = self.params.boundary_margin;
+        let ramp = |dist: f32| (1. - dist / margin).max(0.);
+        let p = boid.position;
+        let away = Vector2::new(
+            ramp(p.x) - ramp(self.width - p.x),
+            ramp(p.y) - ramp(self.height - p.y),
+        );
+        if away.magnitude2() > 0. {
+            let species = self.params.species(boid.species);
+            let target_vel = away.normalize_to(species.max_speed);
+            let strength = away.magnitude().min(1.);
+            strength * self.params.avoid_weight * species.steer(target_vel, boid.velocity, dt)
+        } else {
+            Force::new(0., 0.)
+        }
+    }
 
-            (false, true, false) => &[
-                (1, 1), (0, 1), (-1, 1), (0, 2), (-1, 2),
-                (-1, 0), (-2, 2), (1, 0), (1, 2), (-2, 1),
-            ],
+    fn flee_predators(&self, boid: &Boid, dt: f32) -> Force {
+        let flee_radius = self.params.predators.flee_radius;
+        let mut away = Vector2::new(0., 0.);
+        for predator in &self.predators {
+            let from_predator = boid.position - predator.position;
+            let dist = from_predator.magnitude();
+            if dist > 0. && dist < flee_radius {
+                away += from_predator / dist * (1. - dist / flee_radius);
+            }
+        }
+        if away.magnitude2() > 0. {
+            let species = self.params.species(boid.species);
+            let target_vel = away.normalize_to(species.max_speed);
+            self.params.predators.flee_weight * species.steer(target_vel, boid.velocity, dt)
+        } else {
+            Force::new(0., 0.)
+        }
+    }
 
-            (false, true, true) => &[
-                (-1, 1), (-1, 0), (-1, -1), (-2, 0), (-2, 1),
-                (0, 1), (-2, 2), (0, -1), (-1, 2), (-2, -1),
-            ],
+    // Calls `found` with the grid index of each neighbour candidate the kernel finds in view
+    fn find_neighbours<F>(&self, col: usize, row: usize, boid: &Boid, mut found: F)
+    where
+        F: FnMut(usize, Position),
+    {
+        // Sampled cells change with every boid and every step, but replay exactly
+        let seed = (self.steps << 32) ^ (col + row * self.dim_x) as u64;
+        self.kernel.for_each_offset(boid.velocity, seed, |x, y| {
+            // Offsets off the low edge wrap around to huge values, and fail the bounds check
+            let nx = (col as i32 + x) as usize;
+            let ny = (row as i32 + y) as usize;
+            if nx < self.dim_x && ny < self.dim_y {
+                let index = nx + (ny * self.dim_x);
+                let other = unsafe { *self.boid_grid.positions.get_unchecked(index) };
+                if !other.x.is_infinite() && self.in_view(boid, other) {
+                    found(index, other);
+                }
+            }
+        });
+    }
 
-            (false, false, true) => &[
-                (-1, 1), (-1, 0), (-1, -1), (-2, 0), (-2, -1),
-                (0, -1), (-2, -2), (0, 1), (-2, 1), (-1, -2),
-            ],
+    // Whether the other boid is within the field of view, centred on the boid's heading
+    fn in_view(&self, boid: &Boid, other: Position) -> bool {
+        if self.fov_cos <= -1. || boid.velocity.magnitude2() == 0. {
+            return true;
+        }
+        let to_other = other - boid.position;
+        let dist = to_other.magnitude();
+        dist == 0. || boid.velocity.normalize().dot(to_other) >= self.fov_cos * dist
+    }
 
-            (false, false, false) => &[
-                (-1, -1), (0, -1), (1, -1), (0, -2), (-1, -2),
-                (-1, 0), (-2, -2), (1, 0), (-2, -1), (1, -2),
-            ],
+    // Every boid in view within the largest radius of the boid's species, the boid itself
+    // included. Needs the spatial hash to have been rebuilt since the boids last moved.
+    fn find_exact_neighbours<F>(&self, boid: &Boid, mut found: F)
+    where
+        F: FnMut(usize, Position),
+    {
+        let radius = self.params.species(boid.species).max_radius();
+        self.spatial_hash
+            .for_each_within(boid.position, radius, |index| {
+                let other = self.boid_grid.positions[index];
+                if self.in_view(boid, other) {
+                    found(index, other);
+                }
+            });
+    }
 
-            (true, false, false) => &[
-                (-1, -1), (0, -1), (1, -1), (0, -2), (1, -2),
-                (1, 0), (2, -2), (-1, 0), (-1, -2), (2, -1),
-            ],
+    // Fill in the lanes of the neighbours found for the boid, with how it reacts to each of them
+    fn gather(&self, boid: &Boid, lanes: &mut NeighbourLanes) {
+        let grid = &self.boid_grid;
+        lanes.fill(|other| {
+            let interaction = self.params.interaction(boid.species, grid.species[other]);
+            (grid.velocities[other], interaction)
+        });
+    }
 
-            (true, false, true) => &[
-                (1, -1), (1, 0), (1, 1), (2, 0), (2, -1),
-                (0, -1), (2, -2), (0, 1), (1, -2), (2, 1),
-            ],
-        };
+    fn react_to_neighbours(&self, index: usize, neighbours: &NeighbourLanes, dt: f32) -> Force {
+        let species = self.params.species(self.boid_grid.species[index]);
+        let velocity = self.boid_grid.velocities[index];
+        let sums = neighbours.sum(self.boid_grid.positions[index], species);
 
-        //TODO: Try and remove extra references and casting
-        for &(x, y) in neighbours.iter() {
-            let nx = (col as i32 + x) as usize;
-            let ny = (row as i32 + y) as usize;
-            if nx > 0 && nx < self.dim_x && ny > 0 && ny < self.dim_y {
-                let neighbour = unsafe { self.boid_grid.get_unchecked(nx + (ny * self.dim_x)) };
-                neighbourhood.push(neighbour.clone());
-            }
+        //TODO: Using MAX_SPEED to steer all the things might not be the most pleasing to look at?
+        let mut force = Vector2::new(0., 0.);
+        if sums.dodge.magnitude2() > 0. {
+            let target_d_vel = sums.dodge.normalize_to(species.max_speed);
+            let d_steer = species.steer(target_d_vel, velocity, dt);
+            force += species.sep_weight * d_steer;
         }
+        if sums.ali_vel.magnitude2() > 0. {
+            let target_a_vel = sums.ali_vel.normalize_to(species.max_speed);
+            let a_steer = species.steer(target_a_vel, velocity, dt);
+            force += species.ali_weight * a_steer;
+        }
+        if sums.coh_offset.magnitude2() > 0. {
+            let target_c_vel = sums.coh_offset.normalize_to(species.max_speed);
+            let c_steer = species.steer(target_c_vel, velocity, dt);
+            force += species.coh_weight * c_steer;
+        }
+        force
     }
 
-    fn react_to_neighbours(&self, boid: &Boid, neighbours: &[Boid]) -> Force {
-        let mut dodge = Vector2::new(0., 0.);
-        let mut ali_vel_acc = Vector2::new(0., 0.);
-        let mut ali_vel_count = 0;
-        let mut coh_pos_acc = Vector2::new(0., 0.);
-        let mut coh_pos_count = 0;
-
-        for other in neighbours {
-            let from_neighbour = boid.position - other.position;
-            let dist_squared = from_neighbour.magnitude2();
-            if dist_squared > 0. {
-                if dist_squared < self.params.sep_radius_2 {
-                    let repulse = 1. / dist_squared.sqrt();
-                    dodge += from_neighbour.normalize_to(repulse);
-                }
-                if dist_squared < self.params.ali_radius_2 {
-                    ali_vel_acc += other.velocity;
-                    ali_vel_count += 1;
+    fn update_boids(&mut self, dt: f32) {
+        let (width, height, dim_x) = (self.width, self.height, self.dim_x);
+        let boundary = self.params.boundary;
+        let schedule = self.force_schedule();
+        let (fresh_forces, params) = (&self.fresh_forces, &self.params);
+        let grid = self.boid_grid.ptr();
+        self.executor.for_each_index(self.dim_y, |row| {
+            let start = row * dim_x;
+            let boids = unsafe { grid.slice(start, dim_x) };
+            for col in 0..dim_x {
+                let position = &mut boids.positions[col];
+                if position.x.is_infinite() {
+                    continue;
                 }
-                if dist_squared < self.params.coh_radius_2 {
-                    coh_pos_acc.x += other.position.x;
-                    coh_pos_acc.y += other.position.y;
-                    coh_pos_count += 1;
+                let (velocity, force) = (&mut boids.velocities[col], &mut boids.forces[col]);
+                if schedule.includes(start + col, row) {
+                    *force = fresh_forces[start + col];
                 }
+
+                // Update velocity
+                let vel = *velocity + *force * dt;
+                *velocity = limit(vel, params.species(boids.species[col]).max_speed);
+
+                // Update position
+                *position += *velocity * dt;
+                apply_boundary(position, velocity, boundary, width, height);
             }
+        });
+        if boundary == Boundary::Open {
+            self.respawn_escaped();
         }
-        //TODO: Using MAX_SPEED to steer all the things might not be the most pleasing to look at?
-        let mut force = Vector2::new(0., 0.);
-        if dodge.magnitude2() > 0. {
-            let target_d_vel = dodge.normalize_to(self.params.max_speed);
-            let d_steer = limit(target_d_vel - boid.velocity, self.params.max_force);
-            force += self.params.sep_weight * d_steer;
-        }
-        if ali_vel_count > 0 {
-            let align = ali_vel_acc / ali_vel_count as f32;
-            let target_a_vel = align.normalize_to(self.params.max_speed);
-            let a_steer = limit(target_a_vel - boid.velocity, self.params.max_force);
-            force += self.params.ali_weight * a_steer;
-        }
-        if coh_pos_count > 0 {
-            let avg_pos = coh_pos_acc / coh_pos_count as f32;
-            let boid_pos = Vector2::new(boid.position.x, boid.position.y);
-            let cohesion = avg_pos - boid_pos;
-            let target_c_vel = cohesion.normalize_to(self.params.max_speed);
-            let c_steer = limit(target_c_vel - boid.velocity, self.params.max_force);
-            force += self.params.coh_weight * c_steer;
+    }
+
+    fn update_predators(&mut self, dt: f32) {
+        if self.predators.is_empty() {
+            return;
         }
-        force
+        let densest = match self.params.predators.strategy {
+            ChaseStrategy::Densest => self.densest_region(),
+            ChaseStrategy::Nearest => None,
+        };
+        // Predators never leave the world, even when boids can
+        let boundary = match self.params.boundary {
+            Boundary::Open => Boundary::Bounce,
+            boundary => boundary,
+        };
+        let mut predators = std::mem::take(&mut self.predators);
+        for predator in &mut predators {
+            let target = densest.or_else(|| self.nearest_boid(predator.position));
+            predator.hunt(target, &self.params.predators, dt);
+            let Predator { position, velocity } = predator;
+            apply_boundary(position, velocity, boundary, self.width, self.height);
+        }
+        self.predators = predators;
+    }
+
+    fn nearest_boid(&self, position: Position) -> Option<Position> {
+        self.boids()
+            .map(|b| (b.position, (b.position - position).magnitude2()))
+            .fold(None, |nearest, (p, d)| match nearest {
+                Some((_, nearest_d)) if nearest_d <= d => nearest,
+                _ => Some((p, d)),
+            })
+            .map(|(p, _)| p)
     }
 
-    fn update_boids(&mut self) {
-        for (mut boid, force) in self.boid_grid.iter_mut().zip(self.forces.iter()) {
-            // Update velocity
-            let vel = boid.velocity + force;
-            boid.velocity = limit(vel, self.params.max_speed);
+    // The average position of the boids in the busiest cell of a coarse grid over the world
+    fn densest_region(&self) -> Option<Position> {
+        let cell_size = (2. * self.params.predators.flee_radius).max(1.);
+        let cols = (self.width / cell_size).ceil().max(1.) as usize;
+        let rows = (self.height / cell_size).ceil().max(1.) as usize;
+        let mut cells = vec![(0, Vector2::new(0., 0.)); cols * rows];
+        for boid in self.boids() {
+            let col = ((boid.position.x / cell_size) as usize).min(cols - 1);
+            let row = ((boid.position.y / cell_size) as usize).min(rows - 1);
+            let cell = &mut cells[col + row * cols];
+            cell.0 += 1;
+            cell.1 += Vector2::new(boid.position.x, boid.position.y);
+        }
+        cells
+            .iter()
+            .fold(
+                None,
+                |busiest: Option<&(u32, Vector2<f32>)>, cell| match busiest {
+                    Some(b) if b.0 >= cell.0 => Some(b),
+                    _ => Some(cell),
+                },
+            )
+            .filter(|(count, _)| *count > 0)
+            .map(|&(count, sum)| Position::new(sum.x / count as f32, sum.y / count as f32))
+    }
+
+    // Bring boids that have left an open world back in from a random edge
+    fn respawn_escaped(&mut self) {
+        let (width, height) = (self.width, self.height);
+        let escaped = |p: Position| p.x < 0. || p.x > width || p.y < 0. || p.y > height;
+        let grid = &mut self.boid_grid;
+        for index in 0..grid.len() {
+            if grid.is_sentinel(index) || !escaped(grid.positions[index]) {
+                continue;
+            }
+            let along = self.rng.gen_range(0f32..1.);
+            let (position, inwards) = match self.rng.gen_range(0..4) {
+                0 => (Position::new(0., along * height), Vector2::new(1., 0.)),
+                1 => (Position::new(width, along * height), Vector2::new(-1., 0.)),
+                2 => (Position::new(along * width, 0.), Vector2::new(0., 1.)),
+                _ => (Position::new(along * width, height), Vector2::new(0., -1.)),
+            };
+            let spread = self.rng.gen_range(-PI / 3.0..PI / 3.0);
+            let max_speed = self.params.species(grid.species[index]).max_speed;
+            let speed = self.rng.gen_range(max_speed / 2.0..max_speed);
+            grid.positions[index] = position;
+            grid.velocities[index] = Basis2::from_angle(Rad(spread)).rotate_vector(inwards * speed);
+            grid.forces[index] = Force::new(0., 0.);
+        }
+    }
+}
 
-            // Update position
-            let mut new_pos = boid.position + boid.velocity;
-            if new_pos.x <= 0. {
-                new_pos.x += self.width;
+fn apply_boundary(
+    pos: &mut Position,
+    vel: &mut Velocity,
+    boundary: Boundary,
+    width: f32,
+    height: f32,
+) {
+    match boundary {
+        Boundary::Wrap => {
+            if pos.x <= 0. {
+                pos.x += width;
             }
-            if new_pos.y <= 0. {
-                new_pos.y += self.height;
+            if pos.y <= 0. {
+                pos.y += height;
             }
-            if new_pos.x >= self.width {
-                new_pos.x -= self.width;
+            if pos.x >= width {
+                pos.x -= width;
             }
-            if new_pos.y >= self.height {
-                new_pos.y -= self.height;
+            if pos.y >= height {
+                pos.y -= height;
             }
-            boid.position = new_pos
         }
+        // Soft walls should turn boids before they reach the edge, bounce any that slip through
+        Boundary::Bounce | Boundary::SoftWalls => {
+            if pos.x < 0. || pos.x > width {
+                pos.x = if pos.x < 0. {
+                    -pos.x
+                } else {
+                    2. * width - pos.x
+                };
+                vel.x = -vel.x;
+            }
+            if pos.y < 0. || pos.y > height {
+                pos.y = if pos.y < 0. {
+                    -pos.y
+                } else {
+                    2. * height - pos.y
+                };
+                vel.y = -vel.y;
+            }
+            pos.x = pos.x.max(0.).min(width);
+            pos.y = pos.y.max(0.).min(height);
+        }
+        Boundary::Open => (),
     }
 }
 
+// The cells, out of the whole grid, that work out their forces on a particular step
+#[derive(Clone, Copy)]
+enum ForceSchedule {
+    // The band of rows starting at `first`, carrying on from the top once past the bottom
+    Rows {
+        first: usize,
+        band: usize,
+        dim_y: usize,
+    },
+    // Cells where a running total, adding `fraction` for each cell and starting `step` cells
+    // before the first, passes a whole number. That picks the share of cells asked for, spread
+    // evenly, whatever the fraction.
+    Cells {
+        step: u64,
+        fraction: f64,
+    },
+}
+
+impl ForceSchedule {
+    fn any_in_row(self, row: usize) -> bool {
+        match self {
+            ForceSchedule::Rows { first, band, dim_y } => (row + dim_y - first) % dim_y < band,
+            ForceSchedule::Cells { .. } => true,
+        }
+    }
+
+    fn includes(self, index: usize, row: usize) -> bool {
+        match self {
+            ForceSchedule::Rows { .. } => self.any_in_row(row),
+            ForceSchedule::Cells { step, fraction } => {
+                let total = (index as u64 + step) as f64;
+                (total * fraction).floor() > ((total - 1.) * fraction).floor()
+            }
+        }
+    }
+}
+
+// One pass of a shell sort, over `len` boids from `start` spaced `stride` apart, into
+// descending order of the key of their positions
+unsafe fn shell_pass<K>(grid: GridPtr, start: usize, len: usize, stride: usize, gap: usize, key: K)
+where
+    K: Fn(Position) -> f32,
+{
+    let cell = |i: usize| start + i * stride;
+    for i in gap..len {
+        let temp_key = key(grid.position(cell(i)));
+        let mut j = i;
+        // Only the keys are compared, whole boids are only moved once they need to be
+        while j >= gap && key(grid.position(cell(j - gap))) < temp_key {
+            j -= gap;
+        }
+        if j == i {
+            continue;
+        }
+        let temp_boid = grid.read(cell(i));
+        let mut k = i;
+        while k > j {
+            grid.write(cell(k), grid.read(cell(k - gap)));
+            k -= gap;
+        }
+        grid.write(cell(j), temp_boid);
+    }
+}
+
+fn spawn_predator(
+    rng: &mut ChaCha8Rng,
+    conf: &PredatorConfig,
+    width: f32,
+    height: f32,
+) -> Predator {
+    Predator {
+        position: Position::new(rng.gen_range(0f32..width), rng.gen_range(0f32..height)),
+        velocity: velocity_from_polar(rng.gen_range(0f32..TWO_PI), conf.max_speed),
+    }
+}
+
+// Compared against the cosine of the angle to a neighbour, a full circle lets in everything
+fn fov_cos(fov_degrees: f32) -> f32 {
+    if fov_degrees >= 360. {
+        -1.
+    } else {
+        (fov_degrees.to_radians() / 2.).cos()
+    }
+}
+
+fn ratio(part: usize, whole: usize) -> f32 {
+    if whole > 0 {
+        part as f32 / whole as f32
+    } else {
+        1.
+    }
+}
+
+// The gaps smaller than a row or column of `len` cells, smallest first
+fn shell_gaps(len: usize) -> Vec<usize> {
+    let mut gaps: Vec<usize> = SHELL_GAPS
+        .iter()
+        .copied()
+        .take_while(|&g| g < len)
+        .collect();
+    if gaps.len() == SHELL_GAPS.len() {
+        let mut gap = SHELL_GAPS[SHELL_GAPS.len() - 1];
+        loop {
+            gap = (gap as f32 * SHELL_GAP_GROWTH) as usize;
+            if gap >= len {
+                break;
+            }
+            gaps.push(gap);
+        }
+    }
+    gaps
+}
+
 fn grid_size(width: f32, height: f32, desired_count: u32) -> (usize, usize) {
     let aspect_ratio = width / height;
     let dim_y_unrounded = (desired_count as f32 / aspect_ratio).sqrt();
     let dim_y = dim_y_unrounded.ceil();
     let dim_x = (dim_y_unrounded * aspect_ratio).ceil();
-    (dim_x as usize, dim_y as usize)
+    // Even with no boids there is a cell, as rows and columns are worked through in chunks
+    (dim_x.max(1.) as usize, dim_y.max(1.) as usize)
 }
 
 fn velocity_from_polar(a: f32, m: f32) -> Velocity {
@@ -427,3 +1352,343 @@ fn limit(force: Force, max: f32) -> Force {
         force
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boids::{build_flocking_config, SimulationConfig};
+
+    const DT: f32 = 1. / 60.;
+
+    // A flock in a world small enough for every boid to be in reach of every other
+    fn flock(sim_config: SimulationConfig) -> FlockingSystem {
+        let sim_config = SimulationConfig {
+            seed: Some(7),
+            threads: 1,
+            ..sim_config
+        };
+        FlockingSystem::new(build_flocking_config(&sim_config, 30., 30.))
+    }
+
+    // Nine boids a few units apart, scattered so no forces cancel out exactly
+    fn cluster(kernel: NeighbourKernel) -> FlockingSystem {
+        let mut flock = flock(SimulationConfig {
+            boid_count: 9,
+            kernel,
+            ..SimulationConfig::default()
+        });
+        let spots = [
+            (10.2, 11.0),
+            (13.9, 10.4),
+            (18.5, 9.7),
+            (9.6, 14.3),
+            (14.8, 13.1),
+            (17.2, 15.6),
+            (11.3, 17.9),
+            (15.1, 18.4),
+            (19.4, 17.2),
+        ];
+        let positions = flock.boid_grid.boid_positions_mut();
+        for (position, (x, y)) in positions.zip(spots) {
+            *position = Position::new(x, y);
+        }
+        flock.randomise_velocities();
+        flock
+    }
+
+    #[test]
+    fn grid_and_spatial_hash_agree_when_the_kernel_covers_the_grid() {
+        let mut flock = cluster(NeighbourKernel::Square5);
+        let c = flock.compare_neighbours(DT);
+        assert_eq!(c.boids, 9);
+        assert!(c.true_neighbours > 0);
+        assert_eq!(c.found, c.true_neighbours);
+        assert!(c.force_error <= 1e-4 * c.exact_force);
+    }
+
+    #[test]
+    fn boids_in_the_first_row_and_column_are_found_as_neighbours() {
+        let flock = flock(SimulationConfig {
+            boid_count: 9,
+            kernel: NeighbourKernel::Square5,
+            fov: 360.,
+            ..SimulationConfig::default()
+        });
+        assert_eq!((flock.dim_x, flock.dim_y), (3, 3));
+        let neighbours = |col: usize, row: usize| {
+            let boid = flock.boid_grid.get(col + row * 3);
+            let mut found = Vec::new();
+            flock.find_neighbours(col, row, &boid, |index, _| found.push(index));
+            found
+        };
+        let corner = neighbours(0, 0);
+        for index in [1, 2, 3, 4, 6] {
+            assert!(corner.contains(&index), "{} not in {:?}", index, corner);
+        }
+        assert!(neighbours(1, 1).contains(&0));
+        assert!(neighbours(2, 0).contains(&0));
+    }
+
+    #[test]
+    fn the_cone_kernel_looks_ahead_of_the_boid() {
+        // Seven by seven, sorted so x and y fall as the column and row rise
+        let mut flock = flock(SimulationConfig {
+            boid_count: 49,
+            kernel: NeighbourKernel::Cone,
+            ..SimulationConfig::default()
+        });
+        assert_eq!((flock.dim_x, flock.dim_y), (7, 7));
+        for (i, position) in flock.boid_grid.boid_positions_mut().enumerate() {
+            let (col, row) = ((i % 7) as f32, (i / 7) as f32);
+            *position = Position::new(29. - col * 4., 29. - row * 4.);
+        }
+        let centre = 3 + 3 * 7;
+        flock.boid_grid.velocities[centre] = Velocity::new(1., 0.);
+        let boid = flock.boid_grid.get(centre);
+        let mut found = Vec::new();
+        flock.find_neighbours(3, 3, &boid, |index, _| found.push(index));
+        // Heading +x, towards lower columns
+        for col in 0..3 {
+            assert!(found.contains(&(col + 3 * 7)), "col {} in {:?}", col, found);
+        }
+        for col in 5..7 {
+            assert!(
+                !found.contains(&(col + 3 * 7)),
+                "col {} in {:?}",
+                col,
+                found
+            );
+        }
+    }
+
+    #[test]
+    fn comparing_neighbours_leaves_the_flock_as_it_was() {
+        let mut flock = cluster(NeighbourKernel::Forward);
+        flock.params.sort = SortMode::Adaptive;
+        let (positions, sort_passes) = (flock.grid_positions().to_vec(), flock.sort_passes());
+        flock.compare_neighbours(DT);
+        assert_eq!(flock.grid_positions(), &positions[..]);
+        assert_eq!(flock.sort_passes(), sort_passes);
+    }
+
+    #[test]
+    fn shell_gaps_finish_on_1_and_stay_inside_the_row() {
+        assert!(shell_gaps(0).is_empty());
+        assert!(shell_gaps(1).is_empty());
+        assert_eq!(shell_gaps(2), [1]);
+        let lens = (2..2_000).chain([1750, 1751, 3937, 3938, 100_000, 10_000_000]);
+        for len in lens {
+            let gaps = shell_gaps(len);
+            // Smallest first, as the sort goes through them backwards
+            assert_eq!(gaps[0], 1, "len {}", len);
+            assert!(
+                gaps.windows(2).all(|w| w[0] < w[1]),
+                "len {}: {:?}",
+                len,
+                gaps
+            );
+            assert!(*gaps.last().unwrap() < len, "len {}: {:?}", len, gaps);
+        }
+    }
+
+    #[test]
+    fn sortedness_counts_neighbouring_cells_in_order() {
+        // Three by three, a boid in every cell
+        let mut flock = flock(SimulationConfig {
+            boid_count: 9,
+            ..SimulationConfig::default()
+        });
+        assert_eq!((flock.dim_x, flock.dim_y), (3, 3));
+        let place = |flock: &mut FlockingSystem, at: &dyn Fn(f32, f32) -> Position| {
+            for (i, position) in flock.boid_grid.boid_positions_mut().enumerate() {
+                *position = at((i % 3) as f32, (i / 3) as f32);
+            }
+        };
+        place(&mut flock, &|col, row| {
+            Position::new(29. - col * 10., 29. - row * 10.)
+        });
+        assert_eq!(flock.sortedness(), 1.);
+        place(&mut flock, &|col, row| Position::new(col * 10., row * 10.));
+        assert_eq!(flock.sortedness(), 0.);
+    }
+
+    #[test]
+    fn a_lone_boid_is_already_sorted() {
+        let flock = flock(SimulationConfig {
+            boid_count: 1,
+            ..SimulationConfig::default()
+        });
+        assert_eq!((flock.dim_x, flock.dim_y), (1, 1));
+        assert_eq!(flock.sortedness(), 1.);
+    }
+
+    #[test]
+    fn a_loaded_snapshot_carries_on_as_the_saved_flock_would() {
+        let predators = PredatorConfig {
+            count: 2,
+            ..PredatorConfig::default()
+        };
+        let mut saved = flock(SimulationConfig {
+            boid_count: 40,
+            predators,
+            ..SimulationConfig::default()
+        });
+        for _ in 0..5 {
+            saved.update(DT);
+        }
+        let mut bytes = Vec::new();
+        saved.save_snapshot(&mut bytes).unwrap();
+
+        let mut loaded = flock(SimulationConfig {
+            boid_count: 10,
+            seed: Some(8),
+            ..SimulationConfig::default()
+        });
+        loaded.load_snapshot(&bytes[..]).unwrap();
+
+        let predator_states = |flock: &FlockingSystem| -> Vec<_> {
+            let predators = flock.predators().iter();
+            predators.map(|p| (p.position, p.velocity)).collect()
+        };
+        // Stepped on afterwards too, as the random number generator has to come back as well
+        for _ in 0..2 {
+            assert_eq!(loaded.steps(), saved.steps());
+            assert_eq!(loaded.boid_count(), saved.boid_count());
+            assert_eq!(loaded.grid_positions(), saved.grid_positions());
+            assert_eq!(loaded.grid_velocities(), saved.grid_velocities());
+            assert_eq!(predator_states(&loaded), predator_states(&saved));
+            saved.update(DT);
+            loaded.update(DT);
+        }
+    }
+
+    #[test]
+    fn a_flock_of_no_boids_still_steps() {
+        let mut flock = flock(SimulationConfig {
+            boid_count: 0,
+            ..SimulationConfig::default()
+        });
+        assert_eq!((flock.dim_x, flock.dim_y), (1, 1));
+        for _ in 0..3 {
+            flock.update(DT);
+        }
+        assert_eq!(flock.boids().count(), 0);
+    }
+
+    #[test]
+    fn snapshots_of_a_grid_without_cells_are_rejected() {
+        let mut empty = flock(SimulationConfig::default());
+        empty.dim_x = 0;
+        empty.boid_grid = BoidGrid::default();
+        empty.boid_count = 0;
+        let mut bytes = Vec::new();
+        empty.save_snapshot(&mut bytes).unwrap();
+
+        let mut flock = flock(SimulationConfig::default());
+        let positions = flock.grid_positions().to_vec();
+        assert!(matches!(
+            flock.load_snapshot(&bytes[..]),
+            Err(SnapshotError::Invalid(_))
+        ));
+        assert_eq!(flock.grid_positions(), &positions[..]);
+        flock.update(DT);
+    }
+
+    #[test]
+    fn predators_chase_the_nearest_boid() {
+        let mut flock = cluster(NeighbourKernel::Forward);
+        let near = Position::new(10.2, 11.0);
+        assert_eq!(flock.nearest_boid(Position::new(9., 9.)), Some(near));
+        flock.predators = vec![Predator {
+            position: Position::new(2., 2.),
+            velocity: Velocity::new(0., 1.),
+        }];
+        let start = (near - flock.predators[0].position).magnitude();
+        flock.update_predators(DT);
+        assert!((near - flock.predators[0].position).magnitude() < start);
+    }
+
+    // Without the parallel feature the thread count is ignored, so there is nothing to compare
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn a_seeded_flock_steps_the_same_on_one_thread_as_on_many() {
+        let step = |threads| {
+            let sim_config = SimulationConfig {
+                boid_count: 500,
+                seed: Some(7),
+                threads,
+                ..SimulationConfig::default()
+            };
+            let mut flock = FlockingSystem::new(build_flocking_config(&sim_config, 200., 200.));
+            for _ in 0..10 {
+                flock.update(DT);
+            }
+            (
+                flock.grid_positions().to_vec(),
+                flock.grid_velocities().to_vec(),
+            )
+        };
+        assert_eq!(step(1), step(4));
+    }
+
+    #[test]
+    fn cells_work_out_the_share_of_forces_asked_for() {
+        for fraction in [0.4, 0.7, 0.9, 0.33, 0.05] {
+            let mut flock = flock(SimulationConfig {
+                boid_count: 400,
+                force_update_fraction: fraction,
+                force_partition: ForcePartition::Cells,
+                ..SimulationConfig::default()
+            });
+            let (dim_x, cells) = (flock.dim_x, flock.dim_x * flock.dim_y);
+            for step in 0..50 {
+                flock.steps = step;
+                let schedule = flock.force_schedule();
+                let updated = (0..cells)
+                    .filter(|&index| schedule.includes(index, index / dim_x))
+                    .count();
+                let share = fraction * cells as f32;
+                assert!(
+                    (updated as f32 - share).abs() <= 1.,
+                    "{} of {} cells at {}",
+                    updated,
+                    cells,
+                    fraction
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn every_boid_has_its_force_worked_out_within_one_over_the_fraction_steps() {
+        for partition in [ForcePartition::Rows, ForcePartition::Cells] {
+            for fraction in [1., 0.5, 0.4, 0.3, 0.25, 0.1, 0.07] {
+                let mut flock = flock(SimulationConfig {
+                    boid_count: 35,
+                    force_update_fraction: fraction,
+                    force_partition: partition,
+                    ..SimulationConfig::default()
+                });
+                let (dim_x, cells) = (flock.dim_x, flock.dim_x * flock.dim_y);
+                let window = (1. / fraction).ceil() as u64;
+                for first in 0..3 * window {
+                    let mut updated = vec![false; cells];
+                    for step in first..first + window {
+                        flock.steps = step;
+                        let schedule = flock.force_schedule();
+                        for (index, done) in updated.iter_mut().enumerate() {
+                            *done |= schedule.includes(index, index / dim_x);
+                        }
+                    }
+                    assert!(
+                        updated.iter().all(|&done| done),
+                        "{:?} at {} from step {}",
+                        partition,
+                        fraction,
+                        first
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use std::time::{Duration, Instant};
+
+// Cap on the number of steps taken in one tick, so a long stall (e.g. dragging the
+// window) doesn't leave the simulation trying to catch up forever.
+const MAX_STEPS_PER_TICK: u32 = 5;
+
+pub struct FixedTimestep {
+    step: Duration,
+    last_ticked: Instant,
+    accumulator: Duration,
+}
+
+impl FixedTimestep {
+    pub fn new(steps_per_second: u32) -> FixedTimestep {
+        FixedTimestep {
+            step: Duration::from_secs(1) / steps_per_second,
+            last_ticked: Instant::now(),
+            accumulator: Duration::new(0, 0),
+        }
+    }
+
+    pub fn step_secs(&self) -> f32 {
+        self.step.as_secs_f32()
+    }
+
+    /// Returns how many fixed steps should be taken to catch up with wall clock time.
+    pub fn tick(&mut self) -> u32 {
+        self.accumulator += self.last_ticked.elapsed();
+        self.last_ticked = Instant::now();
+        let mut steps = 0;
+        while self.accumulator >= self.step {
+            self.accumulator -= self.step;
+            steps += 1;
+        }
+        if steps > MAX_STEPS_PER_TICK {
+            steps = MAX_STEPS_PER_TICK;
+        }
+        steps
+    }
+}
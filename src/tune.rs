@@ -0,0 +1,410 @@
+// Nudging the flocking parameters of a running simulation from the keyboard
+
+use std::fs;
+use std::io;
+
+use crate::boids::SimulationConfig;
+use crate::species::SpeciesConfig;
+use crate::system::{Boundary, FlockingSystem};
+
+#[derive(Clone, Copy, PartialEq)]
+enum SpeciesParam {
+    MaxSpeed,
+    MaxForce,
+    SepWeight,
+    AliWeight,
+    CohWeight,
+    SepRadius,
+    AliRadius,
+    CohRadius,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Param {
+    Species(SpeciesParam),
+    MouseWeight,
+    AvoidWeight,
+    AvoidRadius,
+    BoundaryMargin,
+}
+
+const PARAMS: [Param; 12] = [
+    Param::Species(SpeciesParam::SepWeight),
+    Param::Species(SpeciesParam::AliWeight),
+    Param::Species(SpeciesParam::CohWeight),
+    Param::Species(SpeciesParam::SepRadius),
+    Param::Species(SpeciesParam::AliRadius),
+    Param::Species(SpeciesParam::CohRadius),
+    Param::Species(SpeciesParam::MaxSpeed),
+    Param::Species(SpeciesParam::MaxForce),
+    Param::MouseWeight,
+    Param::AvoidWeight,
+    Param::AvoidRadius,
+    Param::BoundaryMargin,
+];
+
+// The values a parameter can take, matching what the config is checked against
+enum Bound {
+    Finite,
+    NonNegative,
+    Positive,
+}
+
+impl SpeciesParam {
+    fn value(self, s: &SpeciesConfig) -> f32 {
+        match self {
+            SpeciesParam::MaxSpeed => s.max_speed,
+            SpeciesParam::MaxForce => s.max_force,
+            SpeciesParam::SepWeight => s.sep_weight,
+            SpeciesParam::AliWeight => s.ali_weight,
+            SpeciesParam::CohWeight => s.coh_weight,
+            SpeciesParam::SepRadius => s.sep_radius,
+            SpeciesParam::AliRadius => s.ali_radius,
+            SpeciesParam::CohRadius => s.coh_radius,
+        }
+    }
+
+    fn value_mut(self, s: &mut SpeciesConfig) -> &mut f32 {
+        match self {
+            SpeciesParam::MaxSpeed => &mut s.max_speed,
+            SpeciesParam::MaxForce => &mut s.max_force,
+            SpeciesParam::SepWeight => &mut s.sep_weight,
+            SpeciesParam::AliWeight => &mut s.ali_weight,
+            SpeciesParam::CohWeight => &mut s.coh_weight,
+            SpeciesParam::SepRadius => &mut s.sep_radius,
+            SpeciesParam::AliRadius => &mut s.ali_radius,
+            SpeciesParam::CohRadius => &mut s.coh_radius,
+        }
+    }
+}
+
+impl Param {
+    fn name(self) -> &'static str {
+        match self {
+            Param::Species(SpeciesParam::MaxSpeed) => "max_speed",
+            Param::Species(SpeciesParam::MaxForce) => "max_force",
+            Param::Species(SpeciesParam::SepWeight) => "sep_weight",
+            Param::Species(SpeciesParam::AliWeight) => "ali_weight",
+            Param::Species(SpeciesParam::CohWeight) => "coh_weight",
+            Param::Species(SpeciesParam::SepRadius) => "sep_radius",
+            Param::Species(SpeciesParam::AliRadius) => "ali_radius",
+            Param::Species(SpeciesParam::CohRadius) => "coh_radius",
+            Param::MouseWeight => "mouse_weight",
+            Param::AvoidWeight => "avoid_weight",
+            Param::AvoidRadius => "avoid_radius",
+            Param::BoundaryMargin => "boundary_margin",
+        }
+    }
+
+    // How far a single key press moves the value
+    fn step(self) -> f32 {
+        match self {
+            Param::Species(SpeciesParam::MaxSpeed) => 10.,
+            Param::Species(SpeciesParam::MaxForce) => 100.,
+            Param::Species(SpeciesParam::SepWeight)
+            | Param::Species(SpeciesParam::AliWeight)
+            | Param::Species(SpeciesParam::CohWeight) => 0.1,
+            Param::Species(SpeciesParam::SepRadius)
+            | Param::Species(SpeciesParam::AliRadius)
+            | Param::Species(SpeciesParam::CohRadius) => 0.5,
+            Param::MouseWeight => 100_000.,
+            Param::AvoidWeight => 0.5,
+            Param::AvoidRadius => 1.,
+            Param::BoundaryMargin => 5.,
+        }
+    }
+
+    fn bound(self, config: &SimulationConfig) -> Bound {
+        match self {
+            Param::Species(SpeciesParam::MaxSpeed) | Param::Species(SpeciesParam::MaxForce) => {
+                Bound::Positive
+            }
+            Param::Species(SpeciesParam::SepWeight)
+            | Param::Species(SpeciesParam::AliWeight)
+            | Param::Species(SpeciesParam::CohWeight)
+            | Param::MouseWeight
+            | Param::AvoidWeight => Bound::Finite,
+            Param::Species(SpeciesParam::SepRadius)
+            | Param::Species(SpeciesParam::AliRadius)
+            | Param::Species(SpeciesParam::CohRadius)
+            | Param::AvoidRadius => Bound::NonNegative,
+            Param::BoundaryMargin if config.boundary == Boundary::SoftWalls => Bound::Positive,
+            Param::BoundaryMargin => Bound::NonNegative,
+        }
+    }
+
+    // Shared by the whole flock, rather than set for each species
+    fn is_shared(self) -> bool {
+        !matches!(self, Param::Species(_))
+    }
+
+    fn value(self, config: &SimulationConfig, species: &SpeciesConfig) -> f32 {
+        match self {
+            Param::Species(param) => param.value(species),
+            Param::MouseWeight => config.mouse_weight,
+            Param::AvoidWeight => config.avoid_weight,
+            Param::AvoidRadius => config.avoid_radius,
+            Param::BoundaryMargin => config.boundary_margin,
+        }
+    }
+
+    fn value_mut(self, config: &mut SimulationConfig, species: usize) -> &mut f32 {
+        match self {
+            Param::Species(param) => param.value_mut(&mut config.species[species]),
+            Param::MouseWeight => &mut config.mouse_weight,
+            Param::AvoidWeight => &mut config.avoid_weight,
+            Param::AvoidRadius => &mut config.avoid_radius,
+            Param::BoundaryMargin => &mut config.boundary_margin,
+        }
+    }
+
+    fn apply(self, simulation: &mut FlockingSystem, species: usize, value: f32) {
+        match self {
+            Param::Species(SpeciesParam::MaxSpeed) => simulation.set_max_speed(species, value),
+            Param::Species(SpeciesParam::MaxForce) => simulation.set_max_force(species, value),
+            Param::Species(SpeciesParam::SepWeight) => simulation.set_sep_weight(species, value),
+            Param::Species(SpeciesParam::AliWeight) => simulation.set_ali_weight(species, value),
+            Param::Species(SpeciesParam::CohWeight) => simulation.set_coh_weight(species, value),
+            Param::Species(SpeciesParam::SepRadius) => simulation.set_sep_radius(species, value),
+            Param::Species(SpeciesParam::AliRadius) => simulation.set_ali_radius(species, value),
+            Param::Species(SpeciesParam::CohRadius) => simulation.set_coh_radius(species, value),
+            Param::MouseWeight => simulation.set_mouse_weight(value),
+            Param::AvoidWeight => simulation.set_avoid_weight(value),
+            Param::AvoidRadius => simulation.set_avoid_radius(value),
+            Param::BoundaryMargin => simulation.set_boundary_margin(value),
+        }
+    }
+}
+
+/// Values set from the keyboard, laid over the config whenever it is built again.
+#[derive(Clone, Default)]
+pub struct TunedValues {
+    // The last value set for each parameter, with the name of the species it was set for
+    values: Vec<(Param, String, f32)>,
+}
+
+impl TunedValues {
+    fn set(&mut self, param: Param, species: &str, value: f32) {
+        self.values
+            .retain(|(p, s, _)| *p != param || (!param.is_shared() && s != species));
+        self.values.push((param, String::from(species), value));
+    }
+
+    /// Lay the values over a config, skipping those for species it no longer has.
+    pub fn apply(&self, config: &mut SimulationConfig) {
+        for (param, name, value) in &self.values {
+            let species = if param.is_shared() {
+                0
+            } else {
+                if config.species.is_empty() {
+                    config.species = config.species();
+                }
+                match config.species.iter().position(|s| &s.name == name) {
+                    Some(species) => species,
+                    None => continue,
+                }
+            };
+            *param.value_mut(config, species) = *value;
+        }
+    }
+}
+
+/// Which parameter, of which species, the keyboard is adjusting.
+pub struct Tuner {
+    param: usize,
+    species: usize,
+}
+
+impl Tuner {
+    pub fn new() -> Tuner {
+        Tuner {
+            param: 0,
+            species: 0,
+        }
+    }
+
+    pub fn next_param(&mut self) {
+        self.param = (self.param + 1) % PARAMS.len();
+    }
+
+    pub fn next_species(&mut self, config: &SimulationConfig) {
+        let count = config.species().len();
+        self.species = (self.species(config) + 1) % count;
+    }
+
+    pub fn prev_species(&mut self, config: &SimulationConfig) {
+        let count = config.species().len();
+        self.species = (self.species(config) + count - 1) % count;
+    }
+
+    /// Move the selected parameter by `presses` steps, in both the config and the simulation.
+    pub fn nudge(
+        &self,
+        config: &mut SimulationConfig,
+        simulation: &mut FlockingSystem,
+        presses: f32,
+    ) {
+        // The flocking section stands in for a lone species, until it is tuned on its own
+        if config.species.is_empty() {
+            config.species = config.species();
+        }
+        let species = self.species(config);
+        let param = PARAMS[self.param];
+        let bound = param.bound(config);
+        let value = param.value_mut(config, species);
+        // Snapped to whole steps, so that repeated nudges do not gather rounding errors
+        let stepped = ((*value / param.step()).round() + presses) * param.step();
+        *value = match bound {
+            Bound::Finite => stepped,
+            Bound::NonNegative => stepped.max(0.),
+            // 0 itself is not allowed, so a step that would reach it leaves the value be
+            Bound::Positive if stepped > 0. => stepped,
+            Bound::Positive => *value,
+        };
+        let value = *value;
+        param.apply(simulation, species, value);
+        // Kept for when the config is next built again, so a reload does not undo it
+        let name = config.species[species].name.clone();
+        if let Some(source) = config.source.as_mut() {
+            source.tuned_mut().set(param, &name, value);
+        }
+    }
+
+    /// The selected parameter and its value, to show whilst tuning.
+    pub fn readout(&self, config: &SimulationConfig) -> String {
+        let param = PARAMS[self.param];
+        let s = &config.species()[self.species(config)];
+        let value = param.value(config, s);
+        if param.is_shared() {
+            format!("{} = {}", param.name(), value)
+        } else {
+            format!("{} {} = {}", s.name, param.name(), value)
+        }
+    }
+
+    // The species may have changed under the tuner, from a reload or a preset
+    fn species(&self, config: &SimulationConfig) -> usize {
+        let count = config.species().len().max(1);
+        self.species.min(count - 1)
+    }
+}
+
+/// Write the tunable parameters out as a config file, to be used as is or copied from.
+pub fn save_tuned(config: &SimulationConfig, path: &str) -> io::Result<()> {
+    let mut toml = String::from("# Flocking parameters tuned whilst running\n\n[flocking]\n");
+    let species = config.species();
+    for param in PARAMS.iter().filter(|p| p.is_shared()) {
+        toml += &format!("{} = {}\n", param.name(), param.value(config, &species[0]));
+    }
+    for s in species {
+        let name = toml::Value::String(s.name.clone());
+        toml += &format!("\n[[species]]\nname = {}\nshare = {}\n", name, s.share);
+        toml += &format!("max_speed = {}\nmax_force = {}\n", s.max_speed, s.max_force);
+        toml += &format!(
+            "sep_weight = {}\nali_weight = {}\ncoh_weight = {}\n",
+            s.sep_weight, s.ali_weight, s.coh_weight
+        );
+        toml += &format!(
+            "sep_radius = {}\nali_radius = {}\ncoh_radius = {}\n",
+            s.sep_radius, s.ali_radius, s.coh_radius
+        );
+        if let Some((r, g, b)) = s.colour {
+            toml += &format!("colour = [{}, {}, {}]\n", r, g, b);
+        }
+    }
+    fs::write(path, toml)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::boids::build_flocking_config;
+    use crate::config::ConfigSource;
+
+    fn tune(config: &mut SimulationConfig, param: Param, value: f32) {
+        let name = config.species()[0].name.clone();
+        let source = config.source.as_mut().unwrap();
+        source.tuned_mut().set(param, &name, value);
+    }
+
+    // Nudge a parameter of the first species, starting from the given value
+    fn nudge(param: Param, from: f32, presses: f32) -> f32 {
+        let mut config = ConfigSource::default().build().unwrap();
+        config.species = config.species();
+        *param.value_mut(&mut config, 0) = from;
+        let mut simulation = FlockingSystem::new(build_flocking_config(&config, 30., 30.));
+        let tuner = Tuner {
+            param: PARAMS.iter().position(|&p| p == param).unwrap(),
+            species: 0,
+        };
+        tuner.nudge(&mut config, &mut simulation, presses);
+        param.value(&config, &config.species[0])
+    }
+
+    #[test]
+    fn weights_can_be_nudged_below_0() {
+        let weight = nudge(Param::Species(SpeciesParam::SepWeight), 0.1, -3.);
+        assert!((weight + 0.2).abs() < 1e-6, "got {}", weight);
+        assert_eq!(nudge(Param::MouseWeight, 0., -1.), -100_000.);
+    }
+
+    #[test]
+    fn radii_stop_at_0() {
+        assert_eq!(nudge(Param::Species(SpeciesParam::CohRadius), 1., -5.), 0.);
+        assert_eq!(nudge(Param::AvoidRadius, 1., -5.), 0.);
+    }
+
+    #[test]
+    fn speeds_are_left_be_rather_than_reach_0() {
+        let max_speed = Param::Species(SpeciesParam::MaxSpeed);
+        assert_eq!(nudge(max_speed, 30., -3.), 30.);
+        assert_eq!(nudge(max_speed, 5., -1.), 5.);
+        assert_eq!(nudge(max_speed, 30., -2.), 10.);
+    }
+
+    #[test]
+    fn saved_species_names_read_back_as_they_were() {
+        let mut config = SimulationConfig::default();
+        config.species = config.species();
+        config.species[0].name = String::from("say \"hi\" \\ \u{e9}");
+        let path = env::temp_dir().join(format!("tuned-{}.toml", process::id()));
+        let path = path.to_str().unwrap();
+        save_tuned(&config, path).unwrap();
+        let saved: toml::Value = toml::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(
+            saved["species"][0]["name"].as_str(),
+            Some(config.species[0].name.as_str())
+        );
+    }
+
+    #[test]
+    fn building_again_keeps_the_picked_preset_and_tuned_values() {
+        let source = ConfigSource::default();
+        let mut config = source.build_with_preset(Some("swarm")).unwrap();
+        tune(&mut config, Param::MouseWeight, 1234.);
+        tune(&mut config, Param::Species(SpeciesParam::SepWeight), 2.5);
+        tune(&mut config, Param::Species(SpeciesParam::SepWeight), 3.5);
+
+        let rebuilt = config.source.as_ref().unwrap().build().unwrap();
+        assert_eq!(rebuilt.preset.as_deref(), Some("swarm"));
+        assert_eq!(rebuilt.mouse_weight, 1234.);
+        assert_eq!(rebuilt.species()[0].sep_weight, 3.5);
+    }
+
+    #[test]
+    fn picking_a_preset_lets_tuned_values_go() {
+        let mut config = ConfigSource::default().build().unwrap();
+        tune(&mut config, Param::MouseWeight, 1234.);
+
+        let source = config.source.as_ref().unwrap();
+        let picked = source.build_with_preset(None).unwrap();
+        assert_eq!(
+            picked.mouse_weight,
+            SimulationConfig::default().mouse_weight
+        );
+    }
+}
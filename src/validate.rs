@@ -0,0 +1,382 @@
+// Checks run over a finished config before anything is built from it. Values that can not
+// work are errors, values that work but probably do not do what was meant are warnings.
+
+use std::fmt;
+
+use crate::boids::{SimulationConfig, WindowSize};
+use crate::kernel::{self, NeighbourKernel};
+use crate::obstacle::Obstacle;
+use crate::species::SpeciesConfig;
+use crate::system::{Boundary, NeighbourSearch};
+
+// Only this many species get their own colour
+const COLOURED_SPECIES: usize = 8;
+
+/// A config value outside of what it is allowed to be.
+#[derive(Debug)]
+pub struct InvalidField {
+    pub field: String,
+    pub problem: String,
+}
+
+impl fmt::Display for InvalidField {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.field, self.problem)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Validation {
+    pub(crate) errors: Vec<InvalidField>,
+    pub(crate) warnings: Vec<String>,
+}
+
+impl Validation {
+    fn invalid(&mut self, field: &str, problem: String) {
+        self.errors.push(InvalidField {
+            field: field.to_owned(),
+            problem,
+        });
+    }
+
+    fn warn(&mut self, warning: String) {
+        self.warnings.push(warning);
+    }
+
+    fn positive(&mut self, field: &str, value: f32) {
+        if !value.is_finite() || value <= 0. {
+            self.invalid(field, format!("must be greater than 0, got {}", value));
+        }
+    }
+
+    fn non_negative(&mut self, field: &str, value: f32) {
+        if !value.is_finite() || value < 0. {
+            self.invalid(field, format!("must be 0 or more, got {}", value));
+        }
+    }
+
+    fn finite(&mut self, field: &str, value: f32) {
+        if !value.is_finite() {
+            self.invalid(field, format!("must be a finite number, got {}", value));
+        }
+    }
+
+    fn finite_point(&mut self, field: &str, (x, y): (f32, f32)) {
+        if !(x.is_finite() && y.is_finite()) {
+            self.invalid(field, format!("must be finite numbers, got [{}, {}]", x, y));
+        }
+    }
+
+    fn unit(&mut self, field: &str, value: f32) {
+        if !(0. ..=1.).contains(&value) {
+            self.invalid(field, format!("must be between 0 and 1, got {}", value));
+        }
+    }
+}
+
+pub(crate) fn validate(c: &SimulationConfig) -> Validation {
+    let mut v = Validation::default();
+
+    if c.boid_count == 0 {
+        v.invalid("boid_count", String::from("must be at least 1, got 0"));
+    }
+    v.positive("boid_size", c.boid_size);
+    if let WindowSize::Dimensions((w, h)) = c.window_size {
+        if w == 0 || h == 0 {
+            v.invalid(
+                "window.size",
+                format!("must be at least 1 pixel each way, got [{}, {}]", w, h),
+            );
+        }
+    }
+
+    v.finite("flocking.mouse_weight", c.mouse_weight);
+    v.finite("flocking.avoid_weight", c.avoid_weight);
+    v.non_negative("flocking.avoid_radius", c.avoid_radius);
+    if c.sort_passes == 0 {
+        v.invalid(
+            "flocking.sort_passes",
+            String::from("must be at least 1, got 0"),
+        );
+    }
+    v.unit("flocking.sort_target", c.sort_target);
+    if !(c.force_update_fraction > 0. && c.force_update_fraction <= 1.) {
+        v.invalid(
+            "flocking.force_update_fraction",
+            format!(
+                "must be more than 0 and at most 1, got {}",
+                c.force_update_fraction
+            ),
+        );
+    }
+    if !(c.fov > 0. && c.fov <= 360.) {
+        v.invalid(
+            "flocking.fov",
+            format!("must be more than 0 and at most 360 degrees, got {}", c.fov),
+        );
+    }
+    if c.kernel == NeighbourKernel::Sample && !(1..=kernel::MAX_SAMPLES).contains(&c.kernel_samples)
+    {
+        v.invalid(
+            "flocking.kernel_samples",
+            format!(
+                "must be between 1 and {}, got {}",
+                kernel::MAX_SAMPLES,
+                c.kernel_samples
+            ),
+        );
+    }
+    if c.boundary == Boundary::SoftWalls {
+        v.positive("flocking.boundary_margin", c.boundary_margin);
+    } else {
+        v.non_negative("flocking.boundary_margin", c.boundary_margin);
+    }
+
+    let species = c.species();
+    // Without any [[species]], the flocking section is the only species
+    let prefix = |i: usize| {
+        if c.species.is_empty() {
+            String::from("flocking")
+        } else {
+            format!("species[{}]", i)
+        }
+    };
+    for (i, s) in species.iter().enumerate() {
+        validate_species(&mut v, &prefix(i), s);
+    }
+    if !species.iter().any(|s| s.share > 0.) {
+        v.invalid(
+            "species",
+            String::from("need at least one species with a share greater than 0"),
+        );
+    }
+    validate_interactions(&mut v, c, species.len());
+
+    for (i, obstacle) in c.obstacles.iter().enumerate() {
+        validate_obstacle(&mut v, &format!("obstacles[{}]", i), obstacle);
+    }
+
+    let p = &c.predators;
+    if p.count > 0 {
+        v.positive("predators.max_speed", p.max_speed);
+    }
+    v.non_negative("predators.turn_rate", p.turn_rate);
+    v.non_negative("predators.flee_radius", p.flee_radius);
+    v.finite("predators.flee_weight", p.flee_weight);
+
+    warn_suspicious(&mut v, c, &species);
+    v
+}
+
+fn validate_species(v: &mut Validation, prefix: &str, s: &SpeciesConfig) {
+    let field = |name: &str| format!("{}.{}", prefix, name);
+    v.non_negative(&field("share"), s.share);
+    v.positive(&field("max_speed"), s.max_speed);
+    v.positive(&field("max_force"), s.max_force);
+    v.finite(&field("sep_weight"), s.sep_weight);
+    v.finite(&field("ali_weight"), s.ali_weight);
+    v.finite(&field("coh_weight"), s.coh_weight);
+    v.non_negative(&field("sep_radius"), s.sep_radius);
+    v.non_negative(&field("ali_radius"), s.ali_radius);
+    v.non_negative(&field("coh_radius"), s.coh_radius);
+    if let Some((r, g, b)) = s.colour {
+        for value in [r, g, b] {
+            v.unit(&field("colour"), value);
+        }
+    }
+}
+
+fn validate_interactions(v: &mut Validation, c: &SimulationConfig, species_count: usize) {
+    let matrices = [
+        ("interactions.separation", &c.interactions.separation),
+        ("interactions.alignment", &c.interactions.alignment),
+        ("interactions.cohesion", &c.interactions.cohesion),
+    ];
+    for (field, matrix) in matrices {
+        if matrix.len() > species_count || matrix.iter().any(|row| row.len() > species_count) {
+            v.invalid(
+                field,
+                format!(
+                    "must have at most {0} rows of at most {0} values, one per species",
+                    species_count
+                ),
+            );
+        }
+        if matrix.iter().flatten().any(|value| !value.is_finite()) {
+            v.invalid(field, String::from("must only hold finite numbers"));
+        }
+    }
+}
+
+fn validate_obstacle(v: &mut Validation, prefix: &str, obstacle: &Obstacle) {
+    let field = |name: &str| format!("{}.{}", prefix, name);
+    match *obstacle {
+        Obstacle::Circle { centre, radius } => {
+            v.finite_point(&field("centre"), centre);
+            v.positive(&field("radius"), radius);
+        }
+        Obstacle::Rect { min, max } => {
+            v.finite_point(&field("min"), min);
+            v.finite_point(&field("max"), max);
+            // Not finite is already an error, comparing them would only repeat it
+            let finite = [min.0, min.1, max.0, max.1].iter().all(|n| n.is_finite());
+            if finite && !(min.0 < max.0 && min.1 < max.1) {
+                v.invalid(
+                    &field("max"),
+                    format!(
+                        "must be greater than min each way, got min {:?} and max {:?}",
+                        min, max
+                    ),
+                );
+            }
+        }
+        Obstacle::Wall { start, end } => {
+            v.finite_point(&field("start"), start);
+            v.finite_point(&field("end"), end);
+        }
+    }
+}
+
+fn warn_suspicious(v: &mut Validation, c: &SimulationConfig, species: &[SpeciesConfig]) {
+    // Neighbours are only looked for a few grid cells away, each cell holding about one boid
+    if let WindowSize::Dimensions((w, h)) = c.window_size {
+        if c.boid_count > 0 && c.neighbours == NeighbourSearch::Grid {
+            let cell_size = (w as f32 * h as f32 / c.boid_count as f32).sqrt();
+            let reach = cell_size * c.kernel.reach() as f32;
+            for s in species {
+                let furthest = s.sep_radius.max(s.ali_radius).max(s.coh_radius);
+                if furthest > reach {
+                    v.warn(format!(
+                        "species '{}' has a radius of {}, but with {} boids in the window \
+                         neighbours are only found about {:.1} away",
+                        s.name, furthest, c.boid_count, reach
+                    ));
+                }
+            }
+        }
+    }
+
+    for s in species {
+        if s.sep_radius > s.coh_radius && s.coh_weight > 0. {
+            v.warn(format!(
+                "species '{}' separates from neighbours further away than it coheres with them, \
+                 so it is unlikely to flock",
+                s.name
+            ));
+        }
+    }
+
+    let fastest = species.iter().map(|s| s.max_speed).fold(0., f32::max);
+    if c.predators.count > 0 && c.predators.max_speed <= fastest {
+        v.warn(format!(
+            "predators are no faster than the fastest boid ({}), so will struggle to catch any",
+            fastest
+        ));
+    }
+
+    if species.len() > COLOURED_SPECIES && species.iter().any(|s| s.colour.is_some()) {
+        v.warn(format!(
+            "only {} species can be told apart by colour, later ones are drawn like the last",
+            COLOURED_SPECIES
+        ));
+    }
+
+    for (i, s) in species.iter().enumerate() {
+        if species[..i].iter().any(|other| other.name == s.name) {
+            v.warn(format!("more than one species is called '{}'", s.name));
+        }
+    }
+
+    if c.record_path.is_some() && c.record_path == c.replay_path {
+        v.warn(String::from(
+            "recording to the file being replayed will replace it",
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The fields that are in error
+    fn invalid_fields(c: &SimulationConfig) -> Vec<String> {
+        validate(c).errors.into_iter().map(|e| e.field).collect()
+    }
+
+    #[test]
+    fn the_default_config_is_valid_without_warnings() {
+        let v = validate(&SimulationConfig::default());
+        assert!(v.errors.is_empty(), "{:?}", v.errors);
+        assert!(v.warnings.is_empty(), "{:?}", v.warnings);
+    }
+
+    #[test]
+    fn a_field_of_view_of_0_is_invalid() {
+        let c = SimulationConfig {
+            fov: 0.,
+            ..SimulationConfig::default()
+        };
+        assert_eq!(invalid_fields(&c), ["flocking.fov"]);
+    }
+
+    #[test]
+    fn a_force_update_fraction_of_0_is_invalid() {
+        let c = SimulationConfig {
+            force_update_fraction: 0.,
+            ..SimulationConfig::default()
+        };
+        assert_eq!(invalid_fields(&c), ["flocking.force_update_fraction"]);
+    }
+
+    #[test]
+    fn an_interaction_matrix_bigger_than_the_species_count_is_invalid() {
+        let mut c = SimulationConfig::default();
+        c.interactions.cohesion = vec![vec![1., 0.5], vec![0.5, 1.]];
+        assert_eq!(invalid_fields(&c), ["interactions.cohesion"]);
+    }
+
+    #[test]
+    fn obstacles_must_be_placed_at_finite_points() {
+        let c = SimulationConfig {
+            obstacles: vec![
+                Obstacle::Circle {
+                    centre: (f32::NAN, 10.),
+                    radius: 5.,
+                },
+                Obstacle::Rect {
+                    min: (0., 0.),
+                    max: (f32::INFINITY, 10.),
+                },
+                Obstacle::Wall {
+                    start: (0., 0.),
+                    end: (10., f32::NEG_INFINITY),
+                },
+            ],
+            ..SimulationConfig::default()
+        };
+        assert_eq!(
+            invalid_fields(&c),
+            [
+                "obstacles[0].centre",
+                "obstacles[1].max",
+                "obstacles[2].end"
+            ]
+        );
+    }
+
+    #[test]
+    fn species_sharing_a_name_are_warned_about() {
+        let mut c = SimulationConfig::default();
+        c.species = c.species();
+        c.species.push(c.species[0].clone());
+        let v = validate(&c);
+        assert!(v.errors.is_empty(), "{:?}", v.errors);
+        assert_eq!(
+            v.warnings,
+            [format!(
+                "more than one species is called '{}'",
+                c.species[0].name
+            )]
+        );
+    }
+}
@@ -0,0 +1,44 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Notices when a file has been written to, by checking its modified time every so often.
+pub struct FileWatcher {
+    path: PathBuf,
+    check_interval: Duration,
+    last_checked: Instant,
+    last_modified: Option<SystemTime>,
+}
+
+impl FileWatcher {
+    pub fn new<P: Into<PathBuf>>(path: P, check_ms: u64) -> FileWatcher {
+        let path = path.into();
+        let last_modified = modified(&path);
+        FileWatcher {
+            path,
+            check_interval: Duration::from_millis(check_ms),
+            last_checked: Instant::now(),
+            last_modified,
+        }
+    }
+
+    /// Whether the file has changed since it was last seen to.
+    pub fn poll(&mut self) -> bool {
+        if self.last_checked.elapsed() < self.check_interval {
+            return false;
+        }
+        self.last_checked = Instant::now();
+        // Editors that save by replacing the file leave it missing for a moment, wait it out
+        match modified(&self.path) {
+            Some(time) if Some(time) != self.last_modified => {
+                self.last_modified = Some(time);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}